@@ -0,0 +1,8 @@
+OPENQASM 2.0;
+include "qelib1.inc";
+// A GHZ state
+qreg q[3];
+creg c[3];
+h q[0];
+cx q[0], q[2];
+cx q[0], q[1];
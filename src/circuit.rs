@@ -1,24 +1,43 @@
 use crate::parser::{ParseError, parse};
-use crate::term::Term;
+use crate::term::{Rotation, Term};
 
 pub struct Circuit {
-    layers: Vec<Vec<Block>>,
-    input: Vec<bool>,
+    pub(crate) layers: Vec<Vec<Block>>,
+    pub(crate) input: Vec<bool>,
+    // Whether every qubit is measured once the last layer has been applied.
+    pub(crate) measure: bool,
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-enum Block {
+// A gate in a layer of a circuit. C is a CNOT and Swap a SWAP on a wire and the one below it, with
+// the control of a CNOT on the upper wire, and S is the phase gate.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Block {
     I,
     H,
     T,
-    C,
+    X,
+    Z,
     S,
+    C,
+    Swap,
+    Rot(Rotation, f64),
+}
+
+impl Block {
+    // Gets the number of wires the block acts on.
+    pub(crate) fn width(&self) -> usize {
+        match self {
+            Block::C | Block::Swap => 2,
+            _ => 1,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
 pub enum CircuitError {
     EmptyCircuit,
     InvalidChar,
+    InvalidAngle,
     DimMismatch,
 }
 
@@ -28,7 +47,7 @@ pub enum CircuitError {
 // which must be computational basis states '0' or '1'.
 // Each subsequent line should be the gates to apply for a certain layer, ordered top to bottom.
 // For example, the line 'H T C' will apply a Hadamard to the first wire, T to the second, and
-// a CNOT on the third and fourth wires.
+// a CNOT on the third and fourth wires. Rotations are written with their angle, as in 'Rz(0.785)'.
 pub fn parse_circuit(text: &str) -> Result<Circuit, CircuitError> {
     // Parse input layer
     let mut input = Vec::new();
@@ -50,13 +69,31 @@ pub fn parse_circuit(text: &str) -> Result<Circuit, CircuitError> {
     let mut layers = Vec::new();
     let mut cur = Vec::new();
     for line in lines {
-        for c in line.chars() {
+        let mut chars = line.chars();
+        while let Some(c) = chars.next() {
             match c {
                 'I' => cur.push(Block::I),
                 'H' => cur.push(Block::H),
                 'T' => cur.push(Block::T),
                 'C' => cur.push(Block::C),
-                'S' => cur.push(Block::S),
+                'S' => cur.push(Block::Swap),
+                'R' | 'P' => {
+                    // Read the rest of the rotation's name up to its angle
+                    let mut name = c.to_string();
+                    for c in chars.by_ref() {
+                        if c == '(' {
+                            break;
+                        }
+                        name.push(c);
+                    }
+                    let r = Rotation::from_name(&name).ok_or(CircuitError::InvalidChar)?;
+                    let angle: String = chars.by_ref().take_while(|c| *c != ')').collect();
+                    let theta = angle
+                        .trim()
+                        .parse()
+                        .map_err(|_| CircuitError::InvalidAngle)?;
+                    cur.push(Block::Rot(r, theta));
+                }
                 c if c.is_whitespace() => continue,
                 _ => return Err(CircuitError::InvalidChar),
             }
@@ -68,13 +105,7 @@ pub fn parse_circuit(text: &str) -> Result<Circuit, CircuitError> {
     // Do basic checking of dimension for each layer
     let dim = input.len();
     for layer in &layers {
-        let mut acc = 0;
-        for block in layer {
-            match block {
-                Block::C | Block::S => acc += 2,
-                _ => acc += 1,
-            }
-        }
+        let acc: usize = layer.iter().map(Block::width).sum();
         if acc != dim {
             return Err(CircuitError::DimMismatch);
         }
@@ -83,6 +114,7 @@ pub fn parse_circuit(text: &str) -> Result<Circuit, CircuitError> {
     Ok(Circuit {
         layers,
         input,
+        measure: false,
     })
 }
 
@@ -91,7 +123,7 @@ impl Circuit {
     pub fn to_lambda(&self) -> Result<Term, ParseError> {
         // Following this block, input will be a church-encoded n-tuple representing
         // the input layer.
-        let mut input = "(\\f.f".to_string();
+        let mut input = "(\\b.b".to_string();
         for b in &self.input {
             if *b {
                 input += " |1>";
@@ -114,7 +146,7 @@ impl Circuit {
                         cnots.push((idx, idx + 1));
                         idx += 2
                     }
-                    Block::S => idx += 2,
+                    Block::Swap => idx += 2,
                     _ => idx += 1,
                 }
             }
@@ -130,7 +162,7 @@ impl Circuit {
                 cur += &format!("(C (pair x{q1} x{q2}))");
                 cur += &format!(" (\\'x{q1}.\\'x{q2}.");
             }
-            cur += "\\f.f";
+            cur += "\\b.b";
 
             // Construct the output tuple
             let mut idx = 0;
@@ -140,6 +172,10 @@ impl Circuit {
                     Block::I => (),
                     Block::H => cur += "H ",
                     Block::T => cur += "T ",
+                    Block::X => cur += "X ",
+                    Block::Z => cur += "Z ",
+                    Block::S => cur += "S ",
+                    Block::Rot(r, theta) => cur += &format!("{r}({theta}) "),
                     Block::C => {
                         cur += &format!("'x{idx})");
                         idx += 1;
@@ -147,7 +183,7 @@ impl Circuit {
                         idx += 1;
                         continue;
                     }
-                    Block::S => {
+                    Block::Swap => {
                         idx += 1;
                         cur += &format!("x{idx})");
                         idx -= 1;
@@ -170,14 +206,17 @@ impl Circuit {
         for i in 0..self.input.len() {
             forcer += &format!("\\x{i}.");
         }
-        forcer += "\\f.f";
+        forcer += "\\b.b";
         for i in 0..self.input.len() {
             forcer += &format!(" x{i}");
         }
         forcer += ")";
         layers.push(forcer);
 
-        let full_str = layers.join(" ");
+        let mut full_str = layers.join(" ");
+        if self.measure {
+            full_str = format!("M ({full_str})");
+        }
         parse(&mut full_str.chars())
     }
 }
@@ -0,0 +1,402 @@
+use crate::{
+    helpers::{abs, app, nonlinear, nonlinear_abs, var},
+    term::{Const, DEPTH, Term},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    hash::{BuildHasherDefault, Hasher},
+};
+
+// The name a bound variable was written with, kept only so that terms converted back from their
+// nameless form read like the source.
+#[derive(Clone, Debug)]
+pub struct Hint(pub String);
+
+// A term with its bound variables replaced by de Bruijn indices, counting the binders between an
+// occurrence and the lambda binding it. The environment machine runs on this form, while
+// substitution works on named terms. Like terms, they are dropped from an explicit stack rather
+// than recursively.
+pub enum Nameless {
+    Bound(usize),
+    Free(String),
+    Const(Const),
+    Abs(Hint, Box<Nameless>),
+    App(Box<Nameless>, Box<Nameless>),
+    NonlinearAbs(Hint, Box<Nameless>),
+    Nonlinear(Box<Nameless>),
+}
+
+impl Drop for Nameless {
+    fn drop(&mut self) {
+        // Drops the subterms of a term, recursing until the depth runs out and pushing the
+        // subterms below that onto the stack, leaving bound variables in their place.
+        fn dispose(t: &mut Nameless, depth: usize, stack: &mut Vec<Nameless>) {
+            let mut take = |t: &mut Box<Nameless>| {
+                if matches!(
+                    **t,
+                    Nameless::Bound(_) | Nameless::Free(_) | Nameless::Const(_)
+                ) {
+                    return;
+                }
+                let mut t = std::mem::replace(&mut **t, Nameless::Bound(0));
+                match depth {
+                    0 => stack.push(t),
+                    _ => dispose(&mut t, depth - 1, stack),
+                }
+            };
+            match t {
+                Nameless::Bound(_) | Nameless::Free(_) | Nameless::Const(_) => (),
+                Nameless::Abs(_, t) | Nameless::NonlinearAbs(_, t) | Nameless::Nonlinear(t) => {
+                    take(t)
+                }
+                Nameless::App(t1, t2) => {
+                    take(t1);
+                    take(t2);
+                }
+            }
+        }
+
+        let mut stack = Vec::new();
+        dispose(self, DEPTH, &mut stack);
+        while let Some(mut t) = stack.pop() {
+            dispose(&mut t, DEPTH, &mut stack);
+        }
+    }
+}
+
+impl From<&Term> for Nameless {
+    fn from(t: &Term) -> Self {
+        convert(t, &mut Vec::new())
+    }
+}
+
+// The work remaining in a traversal that builds a term bottom-up, so that deeply nested terms are
+// processed on the heap instead of the call stack. Each node is visited, then rebuilt from its
+// converted children once they are on the output stack.
+pub enum Task<'a, T> {
+    Visit(T),
+    Abs(&'a str),
+    NonlinearAbs(&'a str),
+    App,
+    Nonlinear,
+}
+
+// Pops the converted children of a node off the output stack and rebuilds it.
+pub fn build<T>(task: Task<'_, T>, out: &mut Vec<Nameless>) {
+    let mut pop = || Box::new(out.pop().expect("missing converted subterm"));
+    let t = match task {
+        Task::Abs(x) => Nameless::Abs(Hint(x.to_string()), pop()),
+        Task::NonlinearAbs(x) => Nameless::NonlinearAbs(Hint(x.to_string()), pop()),
+        Task::Nonlinear => Nameless::Nonlinear(pop()),
+        Task::App => {
+            let t2 = pop();
+            Nameless::App(pop(), t2)
+        }
+        Task::Visit(_) => unreachable!(),
+    };
+    out.push(t);
+}
+
+// Converts a named term whose enclosing binders are given by the scope, innermost last.
+fn convert<'a>(t: &'a Term, scope: &mut Vec<&'a str>) -> Nameless {
+    let mut stack = vec![Task::Visit(t)];
+    let mut out = Vec::new();
+    while let Some(task) = stack.pop() {
+        match task {
+            Task::Visit(t) => match t {
+                Term::Var(x) => out.push(match scope.iter().rposition(|y| y == x) {
+                    Some(i) => Nameless::Bound(scope.len() - 1 - i),
+                    None => Nameless::Free(x.clone()),
+                }),
+                Term::Const(c) => out.push(Nameless::Const(c.clone())),
+                Term::Abs(x, body) => {
+                    scope.push(x);
+                    stack.push(Task::Abs(x));
+                    stack.push(Task::Visit(body));
+                }
+                Term::NonlinearAbs(x, body) => {
+                    scope.push(x);
+                    stack.push(Task::NonlinearAbs(x));
+                    stack.push(Task::Visit(body));
+                }
+                Term::App(t1, t2) => {
+                    stack.push(Task::App);
+                    stack.push(Task::Visit(t2));
+                    stack.push(Task::Visit(t1));
+                }
+                Term::Nonlinear(t) => {
+                    stack.push(Task::Nonlinear);
+                    stack.push(Task::Visit(t));
+                }
+            },
+            task @ (Task::Abs(_) | Task::NonlinearAbs(_)) => {
+                scope.pop();
+                build(task, &mut out);
+            }
+            task => build(task, &mut out),
+        }
+    }
+    out.pop().expect("missing converted term")
+}
+
+// A node of an interned term, whose subterms are given by their ids.
+#[derive(PartialEq, Eq, Hash)]
+enum Shape<'a> {
+    Bound(usize),
+    Free(&'a str),
+    Const(&'a Const),
+    Abs(usize),
+    App(usize, usize),
+    NonlinearAbs(usize),
+    Nonlinear(usize),
+}
+
+// A hasher for the nodes of interned terms, which are small and hashed once each, for which the
+// default hasher's per-hash setup would cost more than the hashing itself. Each word written is
+// folded in by a multiply, as in rustc's FxHasher.
+#[derive(Default)]
+struct NodeHasher(u64);
+
+impl Hasher for NodeHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(8) {
+            let mut word = [0; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            self.write_u64(u64::from_le_bytes(word));
+        }
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        self.0 = (self.0.rotate_left(5) ^ n).wrapping_mul(0x51_7c_c1_b7_27_22_0a_95);
+    }
+
+    fn write_usize(&mut self, n: usize) {
+        self.write_u64(n as u64);
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+// Hash-conses terms up to alpha-equivalence, so that alpha-equivalent terms get the same id. Each
+// node is looked up by the ids of its subterms, so hashing a term takes time linear in its size
+// and equal subterms are only stored once.
+#[derive(Default)]
+pub struct Interner<'a> {
+    ids: HashMap<Shape<'a>, usize, BuildHasherDefault<NodeHasher>>,
+}
+
+impl<'a> Interner<'a> {
+    pub fn intern(&mut self, t: &'a Term) -> usize {
+        let mut scope: Vec<&str> = Vec::new();
+        let mut stack: Vec<Task<&Term>> = vec![Task::Visit(t)];
+        let mut out = Vec::new();
+        while let Some(task) = stack.pop() {
+            let mut pop = || out.pop().expect("missing interned subterm");
+            let shape = match task {
+                Task::Visit(t) => match t {
+                    Term::Var(x) => match scope.iter().rposition(|y| y == x) {
+                        Some(i) => Shape::Bound(scope.len() - 1 - i),
+                        None => Shape::Free(x),
+                    },
+                    Term::Const(c) => Shape::Const(c),
+                    Term::Abs(x, body) => {
+                        scope.push(x);
+                        stack.extend([Task::Abs(x), Task::Visit(&**body)]);
+                        continue;
+                    }
+                    Term::NonlinearAbs(x, body) => {
+                        scope.push(x);
+                        stack.extend([Task::NonlinearAbs(x), Task::Visit(&**body)]);
+                        continue;
+                    }
+                    Term::App(t1, t2) => {
+                        stack.extend([Task::App, Task::Visit(&**t2), Task::Visit(&**t1)]);
+                        continue;
+                    }
+                    Term::Nonlinear(t) => {
+                        stack.extend([Task::Nonlinear, Task::Visit(&**t)]);
+                        continue;
+                    }
+                },
+                Task::Abs(_) => {
+                    scope.pop();
+                    Shape::Abs(pop())
+                }
+                Task::NonlinearAbs(_) => {
+                    scope.pop();
+                    Shape::NonlinearAbs(pop())
+                }
+                Task::Nonlinear => Shape::Nonlinear(pop()),
+                Task::App => {
+                    let t2 = pop();
+                    Shape::App(pop(), t2)
+                }
+            };
+            let next = self.ids.len();
+            out.push(*self.ids.entry(shape).or_insert(next));
+        }
+        out.pop().expect("missing interned term")
+    }
+}
+
+impl Nameless {
+    // Gets the names of the free variables in this term.
+    fn free_names(&self) -> HashSet<&str> {
+        let mut names = HashSet::new();
+        let mut stack = vec![self];
+        while let Some(t) = stack.pop() {
+            match t {
+                Nameless::Free(x) => {
+                    names.insert(x.as_str());
+                }
+                Nameless::Bound(_) | Nameless::Const(_) => {}
+                Nameless::Abs(_, body) | Nameless::NonlinearAbs(_, body) => stack.push(body),
+                Nameless::App(t1, t2) => {
+                    stack.push(t2);
+                    stack.push(t1);
+                }
+                Nameless::Nonlinear(t) => stack.push(t),
+            }
+        }
+        names
+    }
+
+    // Determines if this term has any free variables.
+    pub fn has_free(&self) -> bool {
+        !self.free_names().is_empty()
+    }
+
+    // Counts the binders enclosing this term that it refers to, as the most that any of its
+    // variables reaches past. The count for each subterm visited is remembered, so that a subterm
+    // is only ever counted once.
+    fn escapes(&self, counts: &mut HashMap<*const Nameless, usize>) -> usize {
+        let mut stack = vec![(self, false)];
+        while let Some((t, counted)) = stack.pop() {
+            let key: *const Nameless = t;
+            if counts.contains_key(&key) {
+                continue;
+            }
+            if !counted {
+                stack.push((t, true));
+                match t {
+                    Nameless::Abs(_, body)
+                    | Nameless::NonlinearAbs(_, body)
+                    | Nameless::Nonlinear(body) => stack.push((body, false)),
+                    Nameless::App(t1, t2) => stack.extend([(&**t1, false), (&**t2, false)]),
+                    Nameless::Bound(_) | Nameless::Free(_) | Nameless::Const(_) => (),
+                }
+                continue;
+            }
+            let count = |t: &Nameless| counts[&(t as *const Nameless)];
+            let n = match t {
+                Nameless::Bound(i) => i + 1,
+                Nameless::Free(_) | Nameless::Const(_) => 0,
+                Nameless::Abs(_, body) | Nameless::NonlinearAbs(_, body) => {
+                    count(body).saturating_sub(1)
+                }
+                Nameless::App(t1, t2) => count(t1).max(count(t2)),
+                Nameless::Nonlinear(t) => count(t),
+            };
+            counts.insert(key, n);
+        }
+        counts[&(self as *const Nameless)]
+    }
+
+    // Converts back to a named term. Each binder keeps its hint unless that would capture a free
+    // variable or a variable bound outside it, in which case it gets a numbered variant.
+    pub fn to_term(&self) -> Term {
+        // The names of the free variables and of the binders in scope, counting those shadowed.
+        struct Names {
+            in_use: HashMap<String, usize>,
+            free: HashSet<String>,
+            scope: Vec<String>,
+            counts: HashMap<*const Nameless, usize>,
+        }
+
+        // Names the binder of the body with the hint and brings it into scope. The hint can
+        // shadow an enclosing binder when the body refers to none of them.
+        fn bind(hint: &str, body: &Nameless, names: &mut Names) {
+            let mut n = 0;
+            let mut name = hint.to_string();
+            let shadows = |name: &str, names: &mut Names| {
+                !names.free.contains(name) && body.escapes(&mut names.counts) <= 1
+            };
+            while names.in_use.contains_key(&name) && !shadows(&name, names) {
+                n += 1;
+                name = format!("{hint}{n}");
+            }
+            *names.in_use.entry(name.clone()).or_default() += 1;
+            names.scope.push(name);
+        }
+
+        // Takes the innermost binder out of scope, giving its name.
+        fn unbind(names: &mut Names) -> String {
+            let name = names.scope.pop().expect("unbalanced scope");
+            if let Some(n) = names.in_use.get_mut(&name) {
+                *n -= 1;
+                if *n == 0 {
+                    names.in_use.remove(&name);
+                }
+            }
+            name
+        }
+
+        let free: HashSet<String> = self.free_names().into_iter().map(String::from).collect();
+        let mut names = Names {
+            in_use: free.iter().map(|x| (x.clone(), 1)).collect(),
+            free,
+            scope: Vec::new(),
+            counts: HashMap::new(),
+        };
+        let mut stack = vec![Task::Visit(self)];
+        let mut out = Vec::new();
+        while let Some(task) = stack.pop() {
+            match task {
+                Task::Visit(t) => match t {
+                    Nameless::Bound(i) => out.push(var(&names.scope[names.scope.len() - 1 - i])),
+                    Nameless::Free(x) => out.push(var(x)),
+                    Nameless::Const(c) => out.push(Term::Const(c.clone())),
+                    Nameless::Abs(Hint(x), body) => {
+                        bind(x, body, &mut names);
+                        stack.push(Task::Abs(x));
+                        stack.push(Task::Visit(body));
+                    }
+                    Nameless::NonlinearAbs(Hint(x), body) => {
+                        bind(x, body, &mut names);
+                        stack.push(Task::NonlinearAbs(x));
+                        stack.push(Task::Visit(body));
+                    }
+                    Nameless::App(t1, t2) => {
+                        stack.push(Task::App);
+                        stack.push(Task::Visit(t2));
+                        stack.push(Task::Visit(t1));
+                    }
+                    Nameless::Nonlinear(t) => {
+                        stack.push(Task::Nonlinear);
+                        stack.push(Task::Visit(t));
+                    }
+                },
+                Task::Abs(_) => {
+                    let body = out.pop().expect("missing converted subterm");
+                    out.push(abs(&unbind(&mut names), body));
+                }
+                Task::NonlinearAbs(_) => {
+                    let body = out.pop().expect("missing converted subterm");
+                    out.push(nonlinear_abs(&unbind(&mut names), body));
+                }
+                Task::App => {
+                    let t2 = out.pop().expect("missing converted subterm");
+                    let t1 = out.pop().expect("missing converted subterm");
+                    out.push(app(t1, t2));
+                }
+                Task::Nonlinear => {
+                    let t = out.pop().expect("missing converted subterm");
+                    out.push(nonlinear(t));
+                }
+            }
+        }
+        out.pop().expect("missing converted term")
+    }
+}
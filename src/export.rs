@@ -0,0 +1,208 @@
+use crate::{
+    pretty::ket_label,
+    term::{Term, Value},
+};
+use num_complex::Complex;
+use std::path::Path;
+
+// The most qubits a state may have to be written out as a dense state vector, which has 2^n
+// entries of 16 bytes each.
+const MAX_QUBITS: usize = 24;
+
+// The file formats a state can be exported to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    // A JSON object listing each branch with its label, amplitude and probability.
+    Json,
+    // A NumPy array of complex amplitudes, indexed by basis state.
+    Npy,
+}
+
+impl Format {
+    // Picks the format of a file from its extension.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "json" => Some(Format::Json),
+            "npy" => Some(Format::Npy),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ExportError {
+    // A state vector was asked for a value whose branches aren't all basis states of one size.
+    NotBasisStates,
+    // A state vector was asked for a mixture, which has none.
+    Mixed,
+    // A state vector was asked for more qubits than it can hold.
+    TooManyQubits(usize),
+}
+
+impl std::fmt::Display for ExportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExportError::NotBasisStates => write!(
+                f,
+                "a state vector needs every branch to be a ket or a tuple of the same number of kets"
+            ),
+            ExportError::Mixed => write!(f, "a mixture has no state vector"),
+            ExportError::TooManyQubits(n) => write!(
+                f,
+                "a state vector over {n} qubits is too large, the most is {MAX_QUBITS}"
+            ),
+        }
+    }
+}
+
+// Serialises a value into the given format.
+pub fn export(v: &Value, format: Format) -> Result<Vec<u8>, ExportError> {
+    match format {
+        Format::Json => Ok(json(v).into_bytes()),
+        Format::Npy => npy(v),
+    }
+}
+
+// Gets the branches of a pure state along with their amplitudes, treating a term as the state
+// with that single branch.
+fn branches(v: &Value) -> Option<Vec<(&Term, Complex<f64>)>> {
+    match v {
+        Value::Term(t) => Some(vec![(t, Complex::new(1.0, 0.0))]),
+        Value::Superpos(s) => Some(s.0.iter().map(|(t, amp)| (t, *amp)).collect()),
+        Value::Mixed(_) => None,
+    }
+}
+
+// Writes a string as a JSON string literal.
+fn string(s: &str) -> String {
+    let mut out = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => out += "\\\"",
+            '\\' => out += "\\\\",
+            '\n' => out += "\\n",
+            '\t' => out += "\\t",
+            c if c.is_control() => out += &format!("\\u{:04x}", c as u32),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// Writes a number as a JSON number, or null if it is infinite or not a number, which JSON can't
+// represent.
+fn number(x: f64) -> String {
+    if x.is_finite() {
+        x.to_string()
+    } else {
+        "null".into()
+    }
+}
+
+// Writes a pure state as a JSON object, with its number of qubits if every branch is a basis state
+// of the same size, and each branch labelled by its basis state like "|01>" or else by its term.
+fn state(branches: &[(&Term, Complex<f64>)], indent: &str) -> String {
+    let sizes: Vec<Option<usize>> = branches
+        .iter()
+        .map(|(t, _)| t.as_basis().map(|bits| bits.len()))
+        .collect();
+    let qubits = match sizes.first() {
+        Some(Some(n)) if sizes.iter().all(|m| *m == Some(*n)) => n.to_string(),
+        _ => "null".into(),
+    };
+
+    let items: Vec<String> = branches
+        .iter()
+        .map(|(t, amp)| {
+            let label = ket_label(t).unwrap_or_else(|| t.to_string());
+            format!(
+                "{indent}    {{\"label\": {}, \"amplitude\": {{\"re\": {}, \"im\": {}}}, \"probability\": {}}}",
+                string(&label),
+                number(amp.re),
+                number(amp.im),
+                number(amp.norm_sqr())
+            )
+        })
+        .collect();
+    format!(
+        "{{\n{indent}  \"qubits\": {qubits},\n{indent}  \"branches\": [\n{}\n{indent}  ]\n{indent}}}",
+        items.join(",\n")
+    )
+}
+
+// Writes a value as JSON: a pure state as an object of its branches, and a mixture as an object
+// listing its states along with their probabilities.
+pub fn json(v: &Value) -> String {
+    let out = match (v, branches(v)) {
+        (_, Some(branches)) => state(&branches, ""),
+        (Value::Mixed(m), None) => {
+            let items: Vec<String> =
+                m.0.iter()
+                    .map(|(v, p)| {
+                        let state = match branches(v) {
+                            Some(branches) => state(&branches, "      "),
+                            None => json(v).trim_end().to_string(),
+                        };
+                        format!(
+                            "    {{\n      \"probability\": {},\n      \"state\": {state}\n    }}",
+                            number(*p)
+                        )
+                    })
+                    .collect();
+            format!("{{\n  \"mixture\": [\n{}\n  ]\n}}", items.join(",\n"))
+        }
+        _ => unreachable!("only mixtures have no branches"),
+    };
+    out + "\n"
+}
+
+// Writes the errors which stopped a state from being computed as a JSON object listing their
+// messages.
+pub fn json_errors(errors: &[String]) -> String {
+    let items: Vec<String> = errors
+        .iter()
+        .map(|e| format!("    {}", string(e)))
+        .collect();
+    format!("{{\n  \"errors\": [\n{}\n  ]\n}}\n", items.join(",\n"))
+}
+
+// Writes a pure state over n qubits as a NumPy .npy file holding its 2^n complex amplitudes,
+// where the first qubit of a tuple is the most significant bit of the index.
+pub fn npy(v: &Value) -> Result<Vec<u8>, ExportError> {
+    let branches = branches(v).ok_or(ExportError::Mixed)?;
+    let states: Option<Vec<Vec<bool>>> = branches.iter().map(|(t, _)| t.as_basis()).collect();
+    let states = states.ok_or(ExportError::NotBasisStates)?;
+    let n = states.first().map_or(0, Vec::len);
+    if states.iter().any(|bits| bits.len() != n) {
+        return Err(ExportError::NotBasisStates);
+    }
+    if n > MAX_QUBITS {
+        return Err(ExportError::TooManyQubits(n));
+    }
+
+    let mut amps = vec![Complex::new(0.0, 0.0); 1 << n];
+    for (bits, (_, amp)) in states.iter().zip(&branches) {
+        let i = bits.iter().fold(0, |i, b| (i << 1) | *b as usize);
+        amps[i] += amp;
+    }
+
+    // The header is a Python dict literal, padded with spaces so that the data starts at a
+    // multiple of 64 bytes from the start of the file.
+    let mut header = format!(
+        "{{'descr': '<c16', 'fortran_order': False, 'shape': ({},), }}",
+        amps.len()
+    );
+    let unpadded = 10 + header.len() + 1;
+    header += &" ".repeat(unpadded.next_multiple_of(64) - unpadded);
+    header.push('\n');
+
+    let mut out = b"\x93NUMPY\x01\x00".to_vec();
+    out.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    out.extend_from_slice(header.as_bytes());
+    for amp in amps {
+        out.extend_from_slice(&amp.re.to_le_bytes());
+        out.extend_from_slice(&amp.im.to_le_bytes());
+    }
+    Ok(out)
+}
@@ -2,7 +2,7 @@ use num::Complex;
 
 use crate::{
     superpos::Superpos,
-    term::{Const, Term, Value},
+    term::{Const, Rotation, Term, Value},
 };
 
 // Convenience function for constructing variable terms.
@@ -32,7 +32,12 @@ pub fn app(a: Term, b: Term) -> Term {
 
 // Convenience function for constructing gates.
 pub fn gate(g: &str) -> Term {
-    Term::Const(Const::Gate(g.to_string()))
+    Term::Const(Const::Gate(g.to_string(), None))
+}
+
+// Convenience function for constructing rotation gates.
+pub fn rotation(r: Rotation, theta: f64) -> Term {
+    Term::Const(Const::Rotation(r, theta, None))
 }
 
 // Convenience function for constructing superpositions.
@@ -51,5 +56,24 @@ pub fn meas() -> Term {
 }
 
 pub fn pair(t1: Term, t2: Term) -> Term {
-    abs("b", app(app(var("b"), t1), t2))
+    tuple(vec![t1, t2])
+}
+
+// Convenience function for constructing church-encoded n-tuples.
+pub fn tuple(ts: Vec<Term>) -> Term {
+    let x = fresh(&ts);
+    abs(&x, ts.into_iter().fold(var(&x), app))
+}
+
+// Picks a name for the variable of a tuple which is not free in any of its elements, the same
+// name the parser gives a tuple written (t1, ..., tn).
+pub fn fresh<'a, I>(elems: I) -> String
+where
+    I: IntoIterator<Item = &'a Term> + Clone,
+{
+    let mut x = "b".to_string();
+    while elems.clone().into_iter().any(|t| t.has_free_var(&x)) {
+        x.push('\'');
+    }
+    x
 }
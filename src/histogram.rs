@@ -0,0 +1,49 @@
+use crate::term::Value;
+use std::fmt::Display;
+
+// The widest bar drawn for the most frequent outcome.
+const BAR_WIDTH: usize = 40;
+
+// Counts how often each normal form occurs over repeated evaluations of a term.
+#[derive(Debug, Clone, Default)]
+pub struct Histogram {
+    counts: Vec<(Value, usize)>,
+    shots: usize,
+}
+
+impl Histogram {
+    // Creates an empty histogram.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Records one evaluation result. Outcomes are identified up to alpha-equivalence.
+    pub fn record(&mut self, v: &Value) {
+        self.shots += 1;
+        if let Some((_, count)) = self.counts.iter_mut().find(|(u, _)| u.alpha_eq(v)) {
+            *count += 1;
+        } else {
+            self.counts.push((v.clone(), 1));
+        }
+    }
+}
+
+impl Display for Histogram {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Outcomes are printed flattened onto a single line.
+        let mut counts: Vec<(String, usize)> = self
+            .counts
+            .iter()
+            .map(|(v, c)| (v.to_string().lines().collect::<Vec<_>>().join(" "), *c))
+            .collect();
+        counts.sort_by(|(k1, c1), (k2, c2)| c2.cmp(c1).then(k1.cmp(k2)));
+        let max = counts.first().map(|(_, c)| *c).unwrap_or(1);
+
+        for (outcome, count) in counts {
+            let freq = count as f64 / self.shots as f64;
+            let bar = "#".repeat(count * BAR_WIDTH / max);
+            writeln!(f, "{count:>8} {freq:>7.3} {bar:<BAR_WIDTH$} {outcome}")?;
+        }
+        write!(f, "{:>8} shots", self.shots)
+    }
+}
@@ -0,0 +1,480 @@
+use crate::{
+    debruijn::{Hint, Nameless, Task, build},
+    term::{Const, DEPTH, EvalError, Evaluator, Term, Value, app_values, is_primitive},
+    trace::StepKind,
+};
+use std::rc::Rc;
+
+// A term compiled for the environment machine. Variables are de Bruijn indices into the
+// environment, and subterms are shared so that a closure can point into a body without copying it.
+#[derive(Debug)]
+enum Code {
+    Bound(usize),
+    Free(String),
+    Const(Const),
+    Abs(String, Rc<Code>),
+    App(Rc<Code>, Rc<Code>),
+    NonlinearAbs(String, Rc<Code>),
+    Nonlinear(Rc<Code>),
+}
+
+// Compiled terms are as deep as the terms they come from, so like terms they are dropped from an
+// explicit stack rather than recursively. Only the subterms no other code shares are dropped
+// here, since the rest live on in the code sharing them.
+impl Drop for Code {
+    fn drop(&mut self) {
+        // Drops the unshared subterms of a piece of code, recursing until the depth runs out and
+        // pushing the subterms below that onto the stack, leaving bound variables in their place.
+        fn dispose(code: &mut Code, depth: usize, stack: &mut Vec<Code>) {
+            let mut take = |code: &mut Rc<Code>| {
+                let Some(code) = Rc::get_mut(code) else {
+                    return;
+                };
+                if matches!(code, Code::Bound(_) | Code::Free(_) | Code::Const(_)) {
+                    return;
+                }
+                let mut code = std::mem::replace(code, Code::Bound(0));
+                match depth {
+                    0 => stack.push(code),
+                    _ => dispose(&mut code, depth - 1, stack),
+                }
+            };
+            match code {
+                Code::Bound(_) | Code::Free(_) | Code::Const(_) => (),
+                Code::Abs(_, body) | Code::NonlinearAbs(_, body) | Code::Nonlinear(body) => {
+                    take(body)
+                }
+                Code::App(t1, t2) => {
+                    take(t1);
+                    take(t2);
+                }
+            }
+        }
+
+        let mut stack = Vec::new();
+        dispose(self, DEPTH, &mut stack);
+        while let Some(mut code) = stack.pop() {
+            dispose(&mut code, DEPTH, &mut stack);
+        }
+    }
+}
+
+fn compile(t: &Nameless) -> Rc<Code> {
+    let mut stack = vec![Task::Visit(t)];
+    let mut out: Vec<Rc<Code>> = Vec::new();
+    while let Some(task) = stack.pop() {
+        let code = match task {
+            Task::Visit(t) => match t {
+                Nameless::Bound(i) => Code::Bound(*i),
+                Nameless::Free(x) => Code::Free(x.clone()),
+                Nameless::Const(c) => Code::Const(c.clone()),
+                Nameless::Abs(Hint(x), body) => {
+                    stack.push(Task::Abs(x));
+                    stack.push(Task::Visit(body));
+                    continue;
+                }
+                Nameless::NonlinearAbs(Hint(x), body) => {
+                    stack.push(Task::NonlinearAbs(x));
+                    stack.push(Task::Visit(body));
+                    continue;
+                }
+                Nameless::App(t1, t2) => {
+                    stack.push(Task::App);
+                    stack.push(Task::Visit(t2));
+                    stack.push(Task::Visit(t1));
+                    continue;
+                }
+                Nameless::Nonlinear(t) => {
+                    stack.push(Task::Nonlinear);
+                    stack.push(Task::Visit(t));
+                    continue;
+                }
+            },
+            task => {
+                let mut pop = || out.pop().expect("missing compiled subterm");
+                match task {
+                    Task::Abs(x) => Code::Abs(x.to_string(), pop()),
+                    Task::NonlinearAbs(x) => Code::NonlinearAbs(x.to_string(), pop()),
+                    Task::Nonlinear => Code::Nonlinear(pop()),
+                    Task::App => {
+                        let t2 = pop();
+                        Code::App(pop(), t2)
+                    }
+                    Task::Visit(_) => unreachable!(),
+                }
+            }
+        };
+        out.push(Rc::new(code));
+    }
+    out.pop().expect("missing compiled term")
+}
+
+// A piece of code together with the values of the variables it refers to.
+#[derive(Clone, Debug)]
+struct Closure {
+    code: Rc<Code>,
+    env: Env,
+}
+
+impl Closure {
+    // Compiles a closed term.
+    fn new(t: &Term) -> Self {
+        Self {
+            code: compile(&Nameless::from(t)),
+            env: Env::default(),
+        }
+    }
+
+    // Reads the closure back as a term by substituting in its environment. Every bound value is
+    // closed, so it can be inserted at any depth without shifting.
+    fn nameless(&self) -> Nameless {
+        let mut stack = vec![Task::Visit((&*self.code, &self.env, 0))];
+        let mut out = Vec::new();
+        while let Some(task) = stack.pop() {
+            let Task::Visit((code, env, depth)) = task else {
+                build(task, &mut out);
+                continue;
+            };
+            match code {
+                Code::Bound(i) if *i < depth => out.push(Nameless::Bound(*i)),
+                Code::Bound(i) => {
+                    let c = env.get(i - depth).closure();
+                    stack.push(Task::Visit((&c.code, &c.env, 0)));
+                }
+                Code::Free(x) => out.push(Nameless::Free(x.clone())),
+                Code::Const(c) => out.push(Nameless::Const(c.clone())),
+                Code::Abs(x, body) => {
+                    stack.push(Task::Abs(x));
+                    stack.push(Task::Visit((body, env, depth + 1)));
+                }
+                Code::App(t1, t2) => {
+                    stack.push(Task::App);
+                    stack.push(Task::Visit((t2, env, depth)));
+                    stack.push(Task::Visit((t1, env, depth)));
+                }
+                Code::NonlinearAbs(x, body) => {
+                    stack.push(Task::NonlinearAbs(x));
+                    stack.push(Task::Visit((body, env, depth + 1)));
+                }
+                Code::Nonlinear(t) => {
+                    stack.push(Task::Nonlinear);
+                    stack.push(Task::Visit((t, env, depth)));
+                }
+            }
+        }
+        out.pop().expect("missing read back term")
+    }
+
+    fn to_term(&self) -> Term {
+        self.nameless().to_term()
+    }
+
+    // Determines if applying this closure to the argument is a beta reduction the machine can
+    // take, as opposed to a gate, a measurement or an error.
+    fn is_beta_redex(&self, arg: &Closure) -> bool {
+        matches!(
+            (&*self.code, &*arg.code),
+            (Code::Abs(_, _), _) | (Code::NonlinearAbs(_, _), Code::Nonlinear(_))
+        )
+    }
+}
+
+// What a variable in the environment stands for. Linear variables are bound to evaluated values,
+// while nonlinear variables are bound to the unevaluated body of a !-term, which is evaluated
+// afresh at each use just as if it had been substituted in. Under a lazy strategy, linear
+// variables are bound to their unevaluated arguments in the same way.
+#[derive(Clone, Debug)]
+enum Binding {
+    Value(Closure),
+    Thunk(Closure),
+}
+
+impl Binding {
+    // Binds a closure to be evaluated at each use. A closure of a bare variable shares the binding
+    // of that variable instead, so that arguments passed on from one abstraction to the next do
+    // not build chains of thunks that every use has to walk.
+    fn thunk(c: Closure) -> Binding {
+        match &*c.code {
+            Code::Bound(i) => c.env.get(*i).clone(),
+            _ => Binding::Thunk(c),
+        }
+    }
+
+    fn closure(&self) -> &Closure {
+        match self {
+            Binding::Value(c) | Binding::Thunk(c) => c,
+        }
+    }
+}
+
+// The bindings of the variables in scope, innermost first, shared between closures.
+#[derive(Clone, Debug, Default)]
+struct Env(Option<Rc<(Binding, Env)>>);
+
+impl Env {
+    fn bind(&self, b: Binding) -> Self {
+        Self(Some(Rc::new((b, self.clone()))))
+    }
+
+    fn get(&self, i: usize) -> &Binding {
+        let mut env = self;
+        for _ in 0..i {
+            env = &env.0.as_ref().expect("unbound de Bruijn index").1;
+        }
+        &env.0.as_ref().expect("unbound de Bruijn index").0
+    }
+}
+
+// Environments can grow as long as the chain of reductions that built them, both along the chain
+// and through the environments of the closures bound in it, so they are freed one frame at a time
+// from a worklist rather than recursively.
+impl Drop for Env {
+    fn drop(&mut self) {
+        let mut stack: Vec<_> = self.0.take().into_iter().collect();
+        while let Some(frame) = stack.pop() {
+            if let Ok((mut binding, mut env)) = Rc::try_unwrap(frame) {
+                let (Binding::Value(c) | Binding::Thunk(c)) = &mut binding;
+                stack.extend(c.env.0.take());
+                stack.extend(env.0.take());
+            }
+        }
+    }
+}
+
+// The values the machine works with. Superpositions and mixtures only arise from gates and
+// measurements, and hold ordinary terms in each branch.
+#[derive(Clone, Debug)]
+enum MachineValue {
+    Closure(Closure),
+    Quantum(Value),
+}
+
+impl MachineValue {
+    fn into_value(self) -> Value {
+        match self {
+            MachineValue::Closure(c) => Value::Term(c.to_term()),
+            MachineValue::Quantum(v) => v,
+        }
+    }
+
+    // Determines if this value is a gate or measurement.
+    fn is_primitive(&self) -> bool {
+        matches!(
+            self,
+            MachineValue::Closure(Closure { code, .. })
+                if matches!(&**code, Code::Const(c) if is_primitive(c))
+        )
+    }
+
+    // Determines if this value is a lambda that the machine applies itself.
+    fn is_abs(&self) -> bool {
+        matches!(
+            self,
+            MachineValue::Closure(Closure { code, .. })
+                if matches!(**code, Code::Abs(_, _) | Code::NonlinearAbs(_, _))
+        )
+    }
+}
+
+impl From<Value> for MachineValue {
+    fn from(v: Value) -> Self {
+        match v {
+            Value::Term(t) => MachineValue::Closure(Closure::new(&t)),
+            v => MachineValue::Quantum(v),
+        }
+    }
+}
+
+enum State {
+    // Evaluating a piece of code in an environment.
+    Eval(Rc<Code>, Env),
+    // Returning a value to the innermost frame of the continuation.
+    Return(MachineValue),
+}
+
+// A frame of the continuation, recording what to do once the current value is known.
+enum Frame {
+    // Evaluate the argument of an application whose function is being evaluated.
+    Arg(Rc<Code>, Env),
+    // Apply the function of an application whose argument is being evaluated.
+    Call(MachineValue),
+}
+
+// Rebuilds the term the machine had reached by placing a value back into the frames waiting on it.
+fn plug(mut v: Value, stack: Vec<Frame>) -> Value {
+    for frame in stack.into_iter().rev() {
+        v = match frame {
+            Frame::Arg(code, env) => app_values(v, Value::Term(Closure { code, env }.to_term())),
+            Frame::Call(f) => app_values(f.into_value(), v),
+        };
+    }
+    v
+}
+
+impl Evaluator {
+    // Evaluates a term on the environment machine, which binds variables in environments
+    // instead of substituting into terms, and only reads closures back into terms when a
+    // gate, measurement or the final result needs them.
+    pub(crate) fn run_machine(&mut self, term: &Term) -> Result<Value, EvalError> {
+        let state = State::Eval(compile(&Nameless::from(term)), Env::default());
+        Ok(self.run(state, Vec::new())?.into_value())
+    }
+
+    // Runs the machine until the continuation is empty.
+    fn run(&mut self, mut state: State, mut stack: Vec<Frame>) -> Result<MachineValue, EvalError> {
+        loop {
+            state = match state {
+                State::Eval(code, env) => match &*code {
+                    Code::App(t1, t2) => {
+                        stack.push(Frame::Arg(t2.clone(), env.clone()));
+                        State::Eval(t1.clone(), env)
+                    }
+                    Code::Bound(i) => match env.get(*i) {
+                        Binding::Value(c) => State::Return(MachineValue::Closure(c.clone())),
+                        Binding::Thunk(c) => State::Eval(c.code.clone(), c.env.clone()),
+                    },
+                    _ => State::Return(MachineValue::Closure(Closure { code, env })),
+                },
+                State::Return(v) => match stack.pop() {
+                    None => return Ok(v),
+                    Some(Frame::Arg(code, env)) => match v {
+                        // The abstraction is applied to the argument as it is, leaving the
+                        // argument to be evaluated where its variable is used.
+                        MachineValue::Closure(f)
+                            if self.is_lazy() && matches!(*f.code, Code::Abs(_, _)) =>
+                        {
+                            let a = Closure { code, env };
+                            if let Err(stop) = self.tick() {
+                                let v =
+                                    app_values(Value::Term(f.to_term()), Value::Term(a.to_term()));
+                                return Err(stop(plug(v, stack)));
+                            }
+                            self.enter(f, a.clone(), Binding::thunk(a))
+                        }
+                        v => {
+                            stack.push(Frame::Call(v));
+                            State::Eval(code, env)
+                        }
+                    },
+                    Some(Frame::Call(f)) => {
+                        if let Err(stop) = self.tick() {
+                            let v = app_values(f.into_value(), v.into_value());
+                            return Err(stop(plug(v, stack)));
+                        }
+                        let v = if f.is_primitive() {
+                            match self.force(v.into_value()) {
+                                Ok(v) => v.into(),
+                                Err(e) => {
+                                    let f = f.into_value();
+                                    return Err(e.within(|v| plug(app_values(f, v), stack)));
+                                }
+                            }
+                        } else {
+                            v
+                        };
+                        match self.call(f, v) {
+                            Ok(state) => state,
+                            Err(e) => return Err(e.within(|v| plug(v, stack))),
+                        }
+                    }
+                },
+            }
+        }
+    }
+
+    // Applies a function to an argument on a fresh continuation, running until the result is in
+    // normal form. This is how the machine forks for each branch of a superposition.
+    fn run_call(&mut self, f: MachineValue, a: MachineValue) -> Result<Value, EvalError> {
+        Ok(self
+            .run(State::Return(a), vec![Frame::Call(f)])?
+            .into_value())
+    }
+
+    // Takes the step for applying a function to an argument.
+    fn call(&mut self, f: MachineValue, a: MachineValue) -> Result<State, EvalError> {
+        let res = match (f, a) {
+            (MachineValue::Quantum(Value::Mixed(m)), a) => {
+                self.distribute(&m, |ev, f| ev.run_call(f.into(), a.clone()))?
+            }
+            (f, MachineValue::Quantum(Value::Mixed(m))) => {
+                self.distribute(&m, |ev, a| ev.run_call(f.clone(), a.into()))?
+            }
+            (
+                MachineValue::Quantum(Value::Superpos(s1)),
+                MachineValue::Quantum(Value::Superpos(s2)),
+            ) => {
+                self.record(StepKind::Split {
+                    branches: s1.0.len() * s2.0.len(),
+                });
+                self.depth += 1;
+                let res = s1.zip_terms(&s2, |t1, t2| {
+                    self.run_call(Value::Term(t1).into(), Value::Term(t2).into())
+                });
+                self.depth -= 1;
+                let mut res = res?;
+                res.merge();
+                res
+            }
+            (MachineValue::Quantum(Value::Superpos(s)), a) => {
+                let mut res =
+                    self.split(&s, |ev, t| ev.run_call(Value::Term(t).into(), a.clone()))?;
+                res.merge();
+                res
+            }
+            (f, MachineValue::Quantum(Value::Superpos(s))) if f.is_abs() => {
+                let mut res =
+                    self.split(&s, |ev, t| ev.run_call(f.clone(), Value::Term(t).into()))?;
+                res.merge();
+                res
+            }
+            (MachineValue::Closure(f), MachineValue::Closure(a)) if f.is_beta_redex(&a) => {
+                return Ok(self.beta(f, a));
+            }
+            // Gates, measurements and ill-typed applications behave as in the substitution
+            // evaluator. Gate images and measured branches are already in normal form.
+            (f, a) => {
+                let mut res = self.apply(f.into_value(), a.into_value())?;
+                if let Value::Superpos(s) = &mut res {
+                    s.merge();
+                }
+                res
+            }
+        };
+        Ok(State::Return(res.into()))
+    }
+
+    // Enters the body of an abstraction with its variable bound to the evaluated argument.
+    fn beta(&mut self, f: Closure, a: Closure) -> State {
+        let binding = match (&*f.code, &*a.code) {
+            (Code::Abs(_, _), _) => Binding::Value(a.clone()),
+            (Code::NonlinearAbs(_, _), Code::Nonlinear(t)) => Binding::thunk(Closure {
+                code: t.clone(),
+                env: a.env.clone(),
+            }),
+            _ => unreachable!(),
+        };
+        self.enter(f, a, binding)
+    }
+
+    // Enters the body of an abstraction with its variable bound as given, where the argument is
+    // only needed for the trace.
+    fn enter(&mut self, f: Closure, a: Closure, binding: Binding) -> State {
+        let (Code::Abs(_, body) | Code::NonlinearAbs(_, body)) = &*f.code else {
+            unreachable!()
+        };
+        let body = body.clone();
+        let env = f.env.bind(binding);
+        if self.is_tracing() {
+            let result = Closure {
+                code: body.clone(),
+                env: env.clone(),
+            };
+            self.record(StepKind::Beta {
+                abs: f.to_term(),
+                arg: a.to_term(),
+                result: result.to_term(),
+            });
+        }
+        State::Eval(body, env)
+    }
+}
@@ -1,37 +1,140 @@
 pub mod circuit;
+pub mod debruijn;
+pub mod export;
 pub mod helpers;
+pub mod histogram;
+pub mod machine;
+pub mod module;
 pub mod parser;
+pub mod pretty;
+pub mod qasm;
 pub mod repl;
+pub mod span;
+pub mod statevec;
 pub mod superpos;
 pub mod term;
+pub mod trace;
+pub mod types;
+pub mod unitary;
 
 use rustyline::Result;
 
 use crate::circuit::parse_circuit;
-use crate::repl::repl;
+use crate::qasm::parse_qasm;
+use crate::repl::{RunMode, repl, run};
+use crate::term::{Backend, Engine, Evaluator, Strategy};
 use std::env;
 use std::fs;
+use std::path::Path;
 use std::process::exit;
 
+// Exits once a module has been run, with a failure if it reported any errors, which it has
+// already written out.
+fn finish(res: std::result::Result<(), Vec<String>>) -> ! {
+    match res {
+        Ok(()) => exit(0),
+        Err(_) => exit(1),
+    }
+}
+
 fn main() -> Result<()> {
-    let mut args = env::args().skip(1);
-    match (args.next().as_deref(), args.next()) {
-        (Some("compile"), Some(path)) => {
-            let src = fs::read_to_string(&path).unwrap();
-            let circ = parse_circuit(&src).unwrap();
+    let mut args = env::args().skip(1).peekable();
+
+    // Leading flags configure how every term in the session is evaluated.
+    let mut evaluator = Evaluator::default();
+    loop {
+        match args.peek().map(String::as_str) {
+            Some("--seed") => {
+                args.next();
+                match args.next().map(|n| n.parse::<u64>()) {
+                    Some(Ok(n)) => evaluator.reseed(n),
+                    _ => {
+                        println!("--seed must be followed by a nonnegative integer.");
+                        exit(1);
+                    }
+                }
+            }
+            Some("--dense") => {
+                args.next();
+                evaluator.set_backend(Backend::Dense);
+            }
+            Some("--machine") => {
+                args.next();
+                evaluator.set_engine(Engine::Machine);
+            }
+            Some("--no-typecheck") => {
+                args.next();
+                evaluator.set_type_checking(false);
+            }
+            Some("--strategy") => {
+                args.next();
+                match args.next().as_deref() {
+                    Some("value") => evaluator.set_strategy(Strategy::ByValue),
+                    Some("name") => evaluator.set_strategy(Strategy::ByName),
+                    Some("normal") => evaluator.set_strategy(Strategy::NormalOrder),
+                    _ => {
+                        println!("--strategy must be followed by value, name or normal.");
+                        exit(1);
+                    }
+                }
+            }
+            _ => break,
+        }
+    }
+
+    let args: Vec<String> = args.collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["compile", path, ..] => {
+            let src = fs::read_to_string(path).unwrap();
+            let circ = if path.ends_with(".qasm") {
+                match parse_qasm(&src) {
+                    Ok(circ) => circ,
+                    Err(e) => {
+                        println!("{path}: {e}");
+                        exit(1);
+                    }
+                }
+            } else {
+                parse_circuit(&src).unwrap()
+            };
             let term = circ.to_lambda().unwrap();
             println!("{}", term);
             exit(0);
         }
 
-        (Some("compile"), None) => {
+        ["compile"] => {
             println!("qlam compile must take a filename as an additional argument.");
             exit(1);
         }
 
+        ["run", "--shots", n, path] => match n.parse() {
+            Ok(n) => finish(run(Path::new(path), RunMode::Shots(n), evaluator)),
+            Err(_) => {
+                println!("--shots must be followed by a nonnegative integer.");
+                exit(1);
+            }
+        },
+
+        ["run", "--output", format, path] => match *format {
+            "text" => finish(run(Path::new(path), RunMode::Evaluate, evaluator)),
+            "json" => finish(run(Path::new(path), RunMode::Json, evaluator)),
+            _ => {
+                println!("--output must be followed by text or json.");
+                exit(1);
+            }
+        },
+
+        ["run", path] => finish(run(Path::new(path), RunMode::Evaluate, evaluator)),
+
+        ["run", ..] => {
+            println!("usage: qlam run [--shots N | --output text|json] <FILEPATH>");
+            exit(1);
+        }
+
         _ => (),
     }
 
     println!("Welcome to qlam. Type Ctrl-D to exit.");
-    repl()
+    repl(evaluator)
 }
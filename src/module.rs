@@ -0,0 +1,68 @@
+use crate::parser::strip_comment;
+
+// A top-level item of a module: an import, command, definition or expression. An item starts on a
+// line beginning in the first column and continues over the indented lines after it, so that a
+// long definition can be spread over several lines.
+#[derive(Debug, Clone, Copy)]
+pub struct Item<'a> {
+    // The offset in characters of the item from the start of the module.
+    pub offset: usize,
+    pub text: &'a str,
+}
+
+impl<'a> Item<'a> {
+    // Gets the offset in characters from the start of the module of a part of the item's text.
+    pub fn offset_of(&self, part: &'a str) -> usize {
+        let start = part.as_ptr() as usize - self.text.as_ptr() as usize;
+        self.offset + self.text[..start].chars().count()
+    }
+
+    // Gets the path of the module imported by an item like import "other.qlam", if it is one.
+    pub fn import(&self) -> Option<&'a str> {
+        let path = strip_comment(self.text)
+            .trim()
+            .strip_prefix("import ")?
+            .trim();
+        path.strip_prefix('"')?.strip_suffix('"')
+    }
+}
+
+// Splits the source of a module into its items. Lines holding only a comment or whitespace belong
+// to no item, so they neither start one nor end one.
+pub fn items(src: &str) -> Vec<Item<'_>> {
+    let mut items: Vec<Item> = Vec::new();
+    // The byte offset where the current item starts, and where its last line so far ends.
+    let mut current: Option<(usize, usize)> = None;
+    let mut start = 0;
+    let mut offset = 0;
+    let mut item_offset = 0;
+    for line in src.split_inclusive('\n') {
+        let end = start + line.trim_end().len();
+        if !strip_comment(line).trim().is_empty() {
+            match current {
+                Some((from, _)) if line.starts_with(char::is_whitespace) => {
+                    current = Some((from, end))
+                }
+                _ => {
+                    if let Some((from, to)) = current {
+                        items.push(Item {
+                            offset: item_offset,
+                            text: &src[from..to],
+                        });
+                    }
+                    current = Some((start, end));
+                    item_offset = offset;
+                }
+            }
+        }
+        start += line.len();
+        offset += line.chars().count();
+    }
+    if let Some((from, to)) = current {
+        items.push(Item {
+            offset: item_offset,
+            text: &src[from..to],
+        });
+    }
+    items
+}
@@ -1,195 +1,952 @@
-use std::str::Chars;
+use std::{borrow::Cow, str::Chars};
 
 use crate::{
-    helpers::{abs, app, gate, ket, meas, nonlinear, nonlinear_abs, var},
-    term::Term,
+    helpers::{abs, app, fresh, gate, ket, meas, nonlinear, nonlinear_abs, rotation, var},
+    span::Span,
+    term::{Const, GATES, Rotation, Term},
 };
 
 #[derive(Debug, Clone)]
 enum Token {
-    LPar(usize),
-    RPar(usize),
-    LKet(usize),
-    RKet(usize),
-    Bit(usize, bool),
-    Lam(usize),
-    NonlinearLam(usize),
+    LPar,
+    RPar,
+    LBracket,
+    RBracket,
+    Comma,
+    LKet,
+    RKet,
+    Bit(bool),
+    Boolean(bool),
+    Lam,
+    NonlinearLam,
     Nonlinear,
     Gate(String),
+    Rotation(Rotation, f64),
     Var(String),
     Meas,
+    Dot,
+    Equals,
+    Let,
+    In,
 }
 
-fn tokenize(input: &mut Chars) -> Vec<Token> {
+// Cuts a line off where a comment starts, at the first "--" outside of a quoted identifier.
+pub fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut chars = line.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '`' => quoted = !quoted,
+            '-' if !quoted && matches!(chars.peek(), Some((_, '-'))) => return &line[..i],
+            _ => (),
+        }
+    }
+    line
+}
+
+// Splits the input into tokens, each with its span, also giving the length of the input.
+fn tokenize(input: &mut Chars) -> Result<(Vec<(Token, Span)>, usize), ParseError> {
     let mut res = Vec::new();
     let mut cur = String::new();
+    let mut cur_start = 0;
     let mut pos = 0;
 
-    for c in input.by_ref() {
+    while let Some(c) = input.next() {
+        let at = pos;
         pos += 1;
         let mut next_token = None;
         match c {
-            c if c.is_whitespace() || c == '.' => (),
-            '\\' | 'λ' => next_token = Some(Token::Lam(pos)),
-            '#' => next_token = Some(Token::NonlinearLam(pos)),
+            c if c.is_whitespace() => (),
+            // A comment, running to the end of the line
+            '-' if input.clone().next() == Some('-') => {
+                for c in input.by_ref() {
+                    pos += 1;
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            '.' => next_token = Some(Token::Dot),
+            '=' => next_token = Some(Token::Equals),
+            '\\' | 'λ' => next_token = Some(Token::Lam),
+            '#' => next_token = Some(Token::NonlinearLam),
             '!' => next_token = Some(Token::Nonlinear),
-            '(' => next_token = Some(Token::LPar(pos)),
-            ')' => next_token = Some(Token::RPar(pos)),
-            '|' => next_token = Some(Token::LKet(pos)),
-            '>' => next_token = Some(Token::RKet(pos)),
+            // A rotation name directly followed by a parenthesised angle, like Rz(0.785)
+            '(' if Rotation::from_name(&cur).is_some() => {
+                let mut angle = String::new();
+                for c in input.by_ref() {
+                    pos += 1;
+                    if c == ')' {
+                        break;
+                    }
+                    angle.push(c);
+                }
+                let r = Rotation::from_name(&cur).unwrap();
+                let theta = angle
+                    .trim()
+                    .parse()
+                    .map_err(|_| ParseError::InvalidAngle(Span::new(at, pos)))?;
+                res.push((Token::Rotation(r, theta), Span::new(cur_start, pos)));
+                cur = String::new();
+                continue;
+            }
+            // A quoted identifier, which may hold any characters, with a backtick written twice
+            '`' => {
+                if !cur.is_empty() {
+                    res.push((word(cur), Span::new(cur_start, at)));
+                    cur = String::new();
+                }
+                let mut name = String::new();
+                loop {
+                    match input.next() {
+                        Some('`') if input.clone().next() == Some('`') => {
+                            input.next();
+                            pos += 2;
+                            name.push('`');
+                        }
+                        Some('`') => break pos += 1,
+                        Some(c) => {
+                            pos += 1;
+                            name.push(c);
+                        }
+                        None => return Err(ParseError::UnclosedQuote(Span::new(at, pos))),
+                    }
+                }
+                res.push((Token::Var(name), Span::new(at, pos)));
+                continue;
+            }
+            '(' => next_token = Some(Token::LPar),
+            ')' => next_token = Some(Token::RPar),
+            '[' => next_token = Some(Token::LBracket),
+            ']' => next_token = Some(Token::RBracket),
+            ',' => next_token = Some(Token::Comma),
+            '|' => next_token = Some(Token::LKet),
+            '>' => next_token = Some(Token::RKet),
             '0' | '1' if !cur.is_empty() => {
                 cur.push(c);
                 continue;
             }
-            '0' => next_token = Some(Token::Bit(pos, false)),
-            '1' => next_token = Some(Token::Bit(pos, true)),
-            'H' => next_token = Some(Token::Gate("H".into())),
-            'C' => next_token = Some(Token::Gate("C".into())),
-            'T' => next_token = Some(Token::Gate("T".into())),
-            'M' => next_token = Some(Token::Meas),
+            '0' => next_token = Some(Token::Bit(false)),
+            '1' => next_token = Some(Token::Bit(true)),
             _ => {
+                if cur.is_empty() {
+                    cur_start = at;
+                }
                 cur.push(c);
                 continue;
             }
         }
 
         if !cur.is_empty() {
-            res.push(Token::Var(cur));
+            res.push((word(cur), Span::new(cur_start, at)));
             cur = String::new();
         }
 
-        if let Some(token) = next_token { res.push(token) }
+        if let Some(token) = next_token {
+            res.push((token, Span::new(at, pos)))
+        }
     }
 
     if !cur.is_empty() {
-        res.push(Token::Var(cur));
+        res.push((word(cur), Span::new(cur_start, pos)));
     }
 
-    res
+    Ok((res, pos))
+}
+
+// Determines if a name can be written as it is, rather than quoted, for it to be read back as the
+// same variable.
+fn is_identifier(x: &str) -> bool {
+    match tokenize(&mut x.chars()) {
+        Ok((tokens, _)) => matches!(&tokens[..], [(Token::Var(y), _)] if y == x),
+        Err(_) => false,
+    }
+}
+
+// Writes a variable so that it is read back as the same variable, quoting it between backticks
+// if it is a keyword or holds characters that would otherwise end it.
+pub(crate) fn identifier(x: &str) -> Cow<'_, str> {
+    if is_identifier(x) {
+        Cow::Borrowed(x)
+    } else {
+        Cow::Owned(format!("`{}`", x.replace('`', "``")))
+    }
+}
+
+// Classifies a completed word as a gate, measurement, keyword, boolean or variable.
+fn word(w: String) -> Token {
+    if GATES.contains(&w.as_str()) {
+        Token::Gate(w)
+    } else if w == "M" {
+        Token::Meas
+    } else if w == "let" {
+        Token::Let
+    } else if w == "in" {
+        Token::In
+    } else if w == "true" || w == "false" {
+        Token::Boolean(w == "true")
+    } else {
+        Token::Var(w)
+    }
 }
 
 #[derive(Debug)]
 pub enum ParseError {
-    UnclosedPar(usize),
-    UnopenedPar(usize),
-    UnopenedKet(usize),
-    UnclosedKet(usize),
-    UnusedNonlinear,
-    LoneQubit(usize),
-    MissingVar(usize),
-    MissingBody(usize),
-    EmptyList,
-}
-
-fn parse_tokens(tokens: &[Token]) -> Result<Term, ParseError> {
+    UnclosedPar(Span),
+    UnopenedPar(Span),
+    UnopenedKet(Span),
+    UnclosedKet(Span),
+    UnusedNonlinear(Span),
+    LoneQubit(Span),
+    MissingVar(Span),
+    MissingBody(Span),
+    InvalidAngle(Span),
+    EmptyList(Span),
+    UnclosedLet(Span),
+    UnopenedIn(Span),
+    MissingEquals(Span),
+    UnexpectedEquals(Span),
+    UnclosedBracket(Span),
+    UnopenedBracket(Span),
+    UnexpectedComma(Span),
+    BadPattern(Span),
+    UnclosedQuote(Span),
+}
+
+impl ParseError {
+    // Gets the span of the input the error points to.
+    pub fn span(&self) -> Span {
+        match self {
+            ParseError::UnclosedPar(span)
+            | ParseError::UnopenedPar(span)
+            | ParseError::UnopenedKet(span)
+            | ParseError::UnclosedKet(span)
+            | ParseError::UnusedNonlinear(span)
+            | ParseError::LoneQubit(span)
+            | ParseError::MissingVar(span)
+            | ParseError::MissingBody(span)
+            | ParseError::InvalidAngle(span)
+            | ParseError::EmptyList(span)
+            | ParseError::UnclosedLet(span)
+            | ParseError::UnopenedIn(span)
+            | ParseError::MissingEquals(span)
+            | ParseError::UnexpectedEquals(span)
+            | ParseError::UnclosedBracket(span)
+            | ParseError::UnopenedBracket(span)
+            | ParseError::UnexpectedComma(span)
+            | ParseError::BadPattern(span)
+            | ParseError::UnclosedQuote(span) => *span,
+        }
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::UnclosedPar(_) => write!(f, "unclosed parenthesis"),
+            ParseError::UnopenedPar(_) => write!(f, "unopened parenthesis"),
+            ParseError::UnopenedKet(_) => write!(f, "unopened ket"),
+            ParseError::UnclosedKet(_) => write!(f, "unclosed ket"),
+            ParseError::UnusedNonlinear(_) => write!(f, "! not followed by a parenthesised term"),
+            ParseError::LoneQubit(_) => write!(f, "bit outside of a ket"),
+            ParseError::MissingVar(_) => write!(f, "lambda or let without a variable"),
+            ParseError::MissingBody(_) => write!(f, "lambda or let without a body"),
+            ParseError::InvalidAngle(_) => write!(f, "invalid rotation angle"),
+            ParseError::EmptyList(_) => write!(f, "empty expression"),
+            ParseError::UnclosedLet(_) => write!(f, "let without an in"),
+            ParseError::UnopenedIn(_) => write!(f, "in without a let"),
+            ParseError::MissingEquals(_) => write!(f, "let without an ="),
+            ParseError::UnexpectedEquals(_) => write!(f, "= outside of a let"),
+            ParseError::UnclosedBracket(_) => write!(f, "unclosed bracket"),
+            ParseError::UnopenedBracket(_) => write!(f, "unopened bracket"),
+            ParseError::UnexpectedComma(_) => write!(f, "comma outside of a tuple or list"),
+            ParseError::BadPattern(_) => {
+                write!(
+                    f,
+                    "let pattern must be two or more variables separated by commas"
+                )
+            }
+            ParseError::UnclosedQuote(_) => write!(f, "unclosed quoted identifier"),
+        }
+    }
+}
+
+// Finds the index of the parenthesis or bracket closing each opening one, if it is closed, along
+// with the indices of the commas directly inside each.
+fn match_parens(tokens: &[(Token, Span)]) -> (Vec<Option<usize>>, Vec<Vec<usize>>) {
+    let mut closing = vec![None; tokens.len()];
+    let mut commas = vec![Vec::new(); tokens.len()];
+    let mut open: Vec<(usize, bool)> = Vec::new();
+    for (i, (token, _)) in tokens.iter().enumerate() {
+        match token {
+            Token::LPar => open.push((i, false)),
+            Token::LBracket => open.push((i, true)),
+            Token::RPar | Token::RBracket => {
+                let bracket = matches!(token, Token::RBracket);
+                if let Some(&(j, b)) = open.last()
+                    && b == bracket
+                {
+                    open.pop();
+                    closing[j] = Some(i);
+                }
+            }
+            Token::Comma => {
+                if let Some(&(j, _)) = open.last() {
+                    commas[j].push(i);
+                }
+            }
+            _ => (),
+        }
+    }
+    (closing, commas)
+}
+
+// Finds the index of the in ending the bound term of each let, if it has one. Each in ends the
+// innermost let before it inside the same parentheses, and no let reaches past a comma.
+fn match_lets(tokens: &[(Token, Span)]) -> Vec<Option<usize>> {
+    let mut closing = vec![None; tokens.len()];
+    let mut open = vec![Vec::new()];
+    for (i, (token, _)) in tokens.iter().enumerate() {
+        match token {
+            Token::LPar | Token::LBracket => open.push(Vec::new()),
+            Token::RPar | Token::RBracket if open.len() > 1 => {
+                open.pop();
+            }
+            Token::Comma => open.last_mut().unwrap().clear(),
+            Token::Let => open.last_mut().unwrap().push(i),
+            Token::In => {
+                if let Some(j) = open.last_mut().unwrap().pop() {
+                    closing[j] = Some(i);
+                }
+            }
+            _ => (),
+        }
+    }
+    closing
+}
+
+// What a scope being parsed will become once it ends.
+enum Scope {
+    // The whole input.
+    Top,
+    // The inside of a pair of parentheses.
+    Group,
+    // A tuple or list, whose elements are each parsed in a component scope.
+    Tuple,
+    List,
+    // An element of the tuple or list opened at the given index, which runs up to the next comma.
+    Component(usize),
+    // The body of a lambda, which runs to the end of the enclosing scope.
+    Lam(String),
+    NonlinearLam(String),
+    // The term bound by a let, which runs up to its in. The span is of the let keyword.
+    Bound(Pattern, Span),
+    // The body of a let, which runs to the end of the enclosing scope, along with its bound term.
+    LetBody(Pattern, Span, Term, usize),
+}
+
+// What a let binds: a variable, which is nonlinear for a let!, or the components of a tuple.
+enum Pattern {
+    Var(String, bool),
+    Tuple(Vec<String>),
+}
+
+// A scope being parsed, along with the terms parsed in it so far and their nodes.
+struct Frame {
+    scope: Scope,
+    span: Span,
+    end: usize,
+    res: Vec<(Term, usize)>,
+    // The ! waiting for the next parenthesised term, if any.
+    bang: Option<Span>,
+}
+
+impl Frame {
+    fn new(scope: Scope, span: Span, end: usize) -> Self {
+        Self {
+            scope,
+            span,
+            end,
+            res: Vec::new(),
+            bang: None,
+        }
+    }
+}
+
+// The span of a parsed term, along with the nodes of its immediate subterms.
+struct Node {
+    span: Span,
+    children: Vec<usize>,
+}
+
+// Adds a node for a term with the given subterms, giving its index.
+fn node(nodes: &mut Vec<Node>, span: Span, children: Vec<usize>) -> usize {
+    nodes.push(Node { span, children });
+    nodes.len() - 1
+}
+
+// Lists the spans of a term and each of its subterms in pre-order, starting from its node.
+fn preorder(nodes: &[Node], root: usize) -> Vec<Span> {
+    let mut spans = Vec::with_capacity(nodes.len());
+    let mut stack = vec![root];
+    while let Some(i) = stack.pop() {
+        spans.push(nodes[i].span);
+        stack.extend(nodes[i].children.iter().rev());
+    }
+    spans
+}
+
+// Combines the terms parsed in a scope into their application.
+fn combine(
+    res: Vec<(Term, usize)>,
+    bang: Option<Span>,
+    span: Span,
+    nodes: &mut Vec<Node>,
+) -> Result<(Term, usize), ParseError> {
+    if let Some(bang) = bang {
+        return Err(ParseError::UnusedNonlinear(bang));
+    }
+
+    let mut res = res.into_iter();
+    let Some(first) = res.next() else {
+        return Err(ParseError::EmptyList(span));
+    };
+    Ok(res.fold(first, |(t1, n1), (t2, n2)| {
+        let span = nodes[n1].span.to(nodes[n2].span);
+        (app(t1, t2), node(nodes, span, vec![n1, n2]))
+    }))
+}
+
+// Reads the variables of a tuple pattern like (x, y) from the tokens between its parentheses, if
+// there are at least two of them separated by commas.
+fn tuple_pattern(tokens: &[(Token, Span)]) -> Option<Vec<String>> {
+    if tokens.len() < 3 || tokens.len().is_multiple_of(2) {
+        return None;
+    }
+    let mut xs = Vec::new();
+    for (k, (token, _)) in tokens.iter().enumerate() {
+        match token {
+            Token::Var(x) if k % 2 == 0 => xs.push(x.clone()),
+            Token::Comma if k % 2 == 1 => (),
+            _ => return None,
+        }
+    }
+    Some(xs)
+}
+
+// Reads the index of a qubit from the inside of a ket like |q3>.
+fn qubit(q: &str) -> Option<usize> {
+    q.strip_prefix('q')?.parse().ok()
+}
+
+// Determines if a term is already a value, which reducing it would leave as it is.
+fn is_value(t: &Term) -> bool {
+    !matches!(t, Term::App(_, _))
+}
+
+// Builds a tuple out of its elements, spanning the given span. A tuple of values is \b.b t1 ... tn
+// itself, while any other is built by applying a function to the elements, so that they are
+// reduced before the tuple is taken apart, just as pair t1 t2 is.
+fn tuple(elems: Vec<(Term, usize)>, span: Span, nodes: &mut Vec<Node>) -> (Term, usize) {
+    if elems.iter().all(|(t, _)| is_value(t)) {
+        return tuple_value(elems, span, nodes);
+    }
+
+    let names: Vec<String> = (1..=elems.len()).map(|i| format!("x{i}")).collect();
+    let params = names
+        .iter()
+        .map(|x| (var(x), node(nodes, span, Vec::new())))
+        .collect();
+    let init = names
+        .iter()
+        .rev()
+        .fold(tuple_value(params, span, nodes), |(t, n), x| {
+            (abs(x, t), node(nodes, span, vec![n]))
+        });
+    elems.into_iter().fold(init, |(t1, n1), (t2, n2)| {
+        (app(t1, t2), node(nodes, span, vec![n1, n2]))
+    })
+}
+
+// Builds the tuple \b.b t1 ... tn out of its elements, spanning the given span.
+fn tuple_value(elems: Vec<(Term, usize)>, span: Span, nodes: &mut Vec<Node>) -> (Term, usize) {
+    let x = fresh(elems.iter().map(|(t, _)| t));
+    let start = Span::new(span.start, span.start + 1);
+    let init = (var(&x), node(nodes, start, Vec::new()));
+    let (body, n) = elems.into_iter().fold(init, |(t1, n1), (t2, n2)| {
+        let span = start.to(nodes[n2].span);
+        (app(t1, t2), node(nodes, span, vec![n1, n2]))
+    });
+    (abs(&x, body), node(nodes, span, vec![n]))
+}
+
+// Builds a church-encoded boolean, #x.#y.x for true or #x.#y.y for false.
+fn boolean(b: bool, span: Span, nodes: &mut Vec<Node>) -> (Term, usize) {
+    let n = node(nodes, span, Vec::new());
+    let n = node(nodes, span, vec![n]);
+    let n = node(nodes, span, vec![n]);
+    let t = nonlinear_abs("x", nonlinear_abs("y", var(if b { "x" } else { "y" })));
+    (t, n)
+}
+
+// Builds a list out of its elements as nested pairs ending in the empty list #x.#y.y, the same as
+// the cons and emp of the standard library.
+fn list(elems: Vec<(Term, usize)>, span: Span, nodes: &mut Vec<Node>) -> (Term, usize) {
+    let end = Span::new(span.end - 1, span.end);
+    let init = boolean(false, end, nodes);
+    elems.into_iter().rev().fold(init, |tail, (head, n)| {
+        let span = nodes[n].span.to(end);
+        tuple(vec![(head, n), tail], span, nodes)
+    })
+}
+
+// Parses a list of tokens, giving the spans of the term's subterms in pre-order. Nested scopes
+// are kept on an explicit stack rather than parsed recursively, so that deeply nested input can't
+// overflow the call stack.
+fn parse_tokens(tokens: &[(Token, Span)], len: usize) -> Result<(Term, Vec<Span>), ParseError> {
+    let (closing, commas) = match_parens(tokens);
+    let ins = match_lets(tokens);
+    let mut nodes = Vec::new();
+    let mut stack = vec![Frame::new(Scope::Top, Span::new(0, len), tokens.len())];
     let mut i = 0;
-    let mut res = Vec::new();
-    let mut next_nonlinear = false;
-    while i < tokens.len() {
-        match &tokens[i] {
-            Token::Nonlinear => {
-                next_nonlinear = true;
-            }
-            Token::LPar(pos) => {
-                let mut depth = 0;
-                let mut j = i + 1;
-                let mut pushed = false;
-                while j < tokens.len() {
-                    match tokens[j] {
-                        Token::LPar(_) => {
-                            depth += 1;
-                        }
-                        Token::RPar(_) => {
-                            if depth == 0 {
-                                let mut inner = parse_tokens(&tokens[i + 1..=j - 1])?;
-                                if next_nonlinear {
-                                    inner = nonlinear(inner);
-                                    next_nonlinear = false;
-                                }
-                                res.push(inner);
-                                pushed = true;
-                                break;
-                            }
-                            depth -= 1;
-                        }
-                        _ => (),
+    while let Some(frame) = stack.last_mut() {
+        if i >= frame.end {
+            let Frame {
+                scope,
+                span,
+                end,
+                res,
+                bang,
+            } = stack.pop().unwrap();
+            let (term, n) = match scope {
+                Scope::Tuple => tuple(res, span, &mut nodes),
+                Scope::List => list(res, span, &mut nodes),
+                _ => combine(res, bang, span, &mut nodes)?,
+            };
+            let Some(parent) = stack.last_mut() else {
+                return Ok((term, preorder(&nodes, n)));
+            };
+            match scope {
+                Scope::Top => unreachable!(),
+                Scope::Component(open) => {
+                    parent.res.push((term, n));
+                    // Carry on with the next element after the comma ending this one.
+                    if end < parent.end {
+                        let next = commas[open].iter().find(|&&c| c > end);
+                        let next = next.copied().unwrap_or(parent.end);
+                        let span = tokens[end].1.to(tokens[next].1);
+                        stack.push(Frame::new(Scope::Component(open), span, next));
+                        i = end + 1;
+                    }
+                }
+                Scope::Group | Scope::Tuple | Scope::List => {
+                    // A parenthesised application spans its parentheses.
+                    if matches!(scope, Scope::Group) && matches!(term, Term::App(_, _)) {
+                        nodes[n].span = span;
+                    }
+                    if let Some(bang) = parent.bang.take() {
+                        let n = node(&mut nodes, bang.to(span), vec![n]);
+                        parent.res.push((nonlinear(term), n));
+                    } else {
+                        parent.res.push((term, n));
                     }
-                    j += 1;
+                    i = end + 1;
+                }
+                Scope::Lam(x) => {
+                    let span = span.to(nodes[n].span);
+                    parent
+                        .res
+                        .push((abs(&x, term), node(&mut nodes, span, vec![n])));
                 }
+                Scope::NonlinearLam(x) => {
+                    let span = span.to(nodes[n].span);
+                    parent
+                        .res
+                        .push((nonlinear_abs(&x, term), node(&mut nodes, span, vec![n])));
+                }
+                Scope::Bound(pattern, keyword) => {
+                    let body = Scope::LetBody(pattern, keyword, term, n);
+                    let end = parent.end;
+                    stack.push(Frame::new(body, tokens[i].1, end));
+                    i += 1;
+                }
+                // let x = t in body is (\x. body) t, and let! x = t in body is (#x. body) !(t)
+                Scope::LetBody(Pattern::Var(x, bang), keyword, bound, m) => {
+                    let span = keyword.to(nodes[n].span);
+                    let (f, arg, m) = if bang {
+                        let bound_span = nodes[m].span;
+                        let wrapped = node(&mut nodes, bound_span, vec![m]);
+                        (nonlinear_abs(&x, term), nonlinear(bound), wrapped)
+                    } else {
+                        (abs(&x, term), bound, m)
+                    };
+                    let f_node = node(&mut nodes, span, vec![n]);
+                    let n = node(&mut nodes, span, vec![f_node, m]);
+                    parent.res.push((app(f, arg), n));
+                }
+                // let (x1, ..., xn) = t in body takes the tuple apart as t (\x1 ... xn. body)
+                Scope::LetBody(Pattern::Tuple(xs), keyword, bound, m) => {
+                    let span = keyword.to(nodes[n].span);
+                    let (f, f_node) = xs.iter().rev().fold((term, n), |(t, n), x| {
+                        (abs(x, t), node(&mut nodes, span, vec![n]))
+                    });
+                    let n = node(&mut nodes, span, vec![m, f_node]);
+                    parent.res.push((app(bound, f), n));
+                }
+            }
+            continue;
+        }
 
-                if !pushed {
-                    return Err(ParseError::UnclosedPar(*pos));
+        let (token, span) = &tokens[i];
+        let span = *span;
+        match token {
+            Token::Nonlinear => {
+                frame.bang = Some(span);
+            }
+            Token::LPar | Token::LBracket => {
+                let Some(j) = closing[i] else {
+                    return Err(match token {
+                        Token::LPar => ParseError::UnclosedPar(span),
+                        _ => ParseError::UnclosedBracket(span),
+                    });
+                };
+                let scope = match token {
+                    Token::LPar if commas[i].is_empty() => Scope::Group,
+                    Token::LPar => Scope::Tuple,
+                    _ => Scope::List,
+                };
+                let group = matches!(scope, Scope::Group);
+                stack.push(Frame::new(scope, span.to(tokens[j].1), j));
+                // The elements of a tuple or list, unless it is the empty list, are each parsed
+                // in their own scope.
+                if !group && j > i + 1 {
+                    let next = commas[i].first().copied().unwrap_or(j);
+                    let scope = Scope::Component(i);
+                    stack.push(Frame::new(scope, span.to(tokens[next].1), next));
                 }
-                i = j;
             }
-            Token::RPar(pos) => return Err(ParseError::UnopenedPar(*pos)),
-            Token::LKet(pos) => {
-                if i < tokens.len() - 2 {
+            Token::RPar => return Err(ParseError::UnopenedPar(span)),
+            Token::RBracket => return Err(ParseError::UnopenedBracket(span)),
+            Token::Comma => return Err(ParseError::UnexpectedComma(span)),
+            Token::Boolean(b) => {
+                let (t, n) = boolean(*b, span, &mut nodes);
+                frame.res.push((t, n));
+            }
+            Token::LKet => {
+                if i + 2 < frame.end {
                     match (&tokens[i + 1], &tokens[i + 2]) {
-                        (Token::Bit(_, b), Token::RKet(_)) => {
-                            res.push(ket(*b));
+                        ((Token::Bit(b), _), (Token::RKet, close)) => {
+                            let n = node(&mut nodes, span.to(*close), Vec::new());
+                            frame.res.push((ket(*b), n));
                             i += 3;
                             continue;
                         }
-                        _ => return Err(ParseError::UnclosedKet(*pos)),
+                        // A qubit of the dense backend's register, as it is printed
+                        ((Token::Var(q), _), (Token::RKet, close)) if qubit(q).is_some() => {
+                            let n = node(&mut nodes, span.to(*close), Vec::new());
+                            frame
+                                .res
+                                .push((Term::Const(Const::Qubit(qubit(q).unwrap())), n));
+                            i += 3;
+                            continue;
+                        }
+                        _ => return Err(ParseError::UnclosedKet(span)),
                     }
                 }
-                return Err(ParseError::UnclosedKet(*pos));
+                return Err(ParseError::UnclosedKet(span));
             }
-            Token::RKet(pos) => return Err(ParseError::UnopenedKet(*pos)),
-            Token::Bit(pos, _) => return Err(ParseError::LoneQubit(*pos)),
-            Token::Lam(pos) => {
-                if tokens.len() <= i + 2 {
-                    return Err(ParseError::MissingBody(*pos));
+            Token::RKet => return Err(ParseError::UnopenedKet(span)),
+            Token::Bit(_) => return Err(ParseError::LoneQubit(span)),
+            // Several variables before a dot, as in \x y. body, are bound by nested lambdas, while
+            // without a dot only the first is bound, as in \x x.
+            Token::Lam | Token::NonlinearLam => {
+                let mut vars = i + 1;
+                while let Some((Token::Var(_), _)) = tokens[..frame.end].get(vars) {
+                    vars += 1;
                 }
-                if let Some(Token::Var(x)) = tokens.get(i + 1) {
-                    let rest = parse_tokens(&tokens[i + 2..])?;
-                    res.push(abs(x, rest));
-                    i = tokens.len();
-                } else {
-                    return Err(ParseError::MissingVar(*pos));
+                if vars == i + 1 {
+                    return Err(ParseError::MissingVar(span));
                 }
-            }
-            Token::NonlinearLam(pos) => {
-                if tokens.len() <= i + 2 {
-                    return Err(ParseError::MissingBody(*pos));
+                if !matches!(tokens[..frame.end].get(vars), Some((Token::Dot, _))) {
+                    vars = i + 2;
                 }
-                if let Some(Token::Var(x)) = tokens.get(i + 1) {
-                    let rest = parse_tokens(&tokens[i + 2..])?;
-                    res.push(nonlinear_abs(x, rest));
-                    i = tokens.len();
-                } else {
-                    return Err(ParseError::MissingVar(*pos));
+                let mut start = vars;
+                while let Some((Token::Dot, _)) = tokens[..frame.end].get(start) {
+                    start += 1;
+                }
+                if frame.end <= start {
+                    return Err(ParseError::MissingBody(span));
                 }
+
+                let end = frame.end;
+                let span = span.to(tokens[start - 1].1);
+                for (token_x, _) in &tokens[i + 1..vars] {
+                    let Token::Var(x) = token_x else {
+                        unreachable!()
+                    };
+                    let scope = match token {
+                        Token::Lam => Scope::Lam(x.clone()),
+                        _ => Scope::NonlinearLam(x.clone()),
+                    };
+                    stack.push(Frame::new(scope, span, end));
+                }
+                i = start;
+                continue;
             }
+            Token::Let => {
+                let Some(j) = ins[i] else {
+                    return Err(ParseError::UnclosedLet(span));
+                };
+                let bang = matches!(tokens[i + 1], (Token::Nonlinear, _));
+                let at = i + 1 + bang as usize;
+                let (pattern, at) = match tokens[..j].get(at) {
+                    Some((Token::Var(x), _)) => (Pattern::Var(x.clone(), bang), at + 1),
+                    Some((Token::LPar, open)) if !bang => {
+                        let Some(close) = closing[at].filter(|&close| close < j) else {
+                            return Err(ParseError::UnclosedPar(*open));
+                        };
+                        match tuple_pattern(&tokens[at + 1..close]) {
+                            Some(xs) => (Pattern::Tuple(xs), close + 1),
+                            None => return Err(ParseError::BadPattern(open.to(tokens[close].1))),
+                        }
+                    }
+                    _ => return Err(ParseError::MissingVar(span)),
+                };
+                let Some((Token::Equals, equals)) = tokens[..j].get(at) else {
+                    return Err(ParseError::MissingEquals(span));
+                };
+                if frame.end <= j + 1 {
+                    return Err(ParseError::MissingBody(span));
+                }
+                let scope = Scope::Bound(pattern, span);
+                stack.push(Frame::new(scope, equals.to(tokens[j].1), j));
+                i = at + 1;
+                continue;
+            }
+            Token::In => return Err(ParseError::UnopenedIn(span)),
+            Token::Equals => return Err(ParseError::UnexpectedEquals(span)),
+            Token::Dot => (),
             Token::Var(x) => {
-                res.push(var(x));
+                let n = node(&mut nodes, span, Vec::new());
+                frame.res.push((var(x), n));
             }
             Token::Gate(g) => {
-                res.push(gate(g));
+                let n = node(&mut nodes, span, Vec::new());
+                frame.res.push((gate(g), n));
+            }
+            Token::Rotation(r, theta) => {
+                let n = node(&mut nodes, span, Vec::new());
+                frame.res.push((rotation(*r, *theta), n));
             }
             Token::Meas => {
-                res.push(meas());
+                let n = node(&mut nodes, span, Vec::new());
+                frame.res.push((meas(), n));
             }
         }
         i += 1;
     }
 
-    if next_nonlinear {
-        return Err(ParseError::UnusedNonlinear);
+    unreachable!()
+}
+
+pub fn parse(input: &mut Chars) -> Result<Term, ParseError> {
+    Ok(parse_spanned(input)?.0)
+}
+
+// Parses a term along with the spans of its subterms in pre-order, so that the span of a subterm
+// is found by counting the subterms before it.
+pub fn parse_spanned(input: &mut Chars) -> Result<(Term, Vec<Span>), ParseError> {
+    let (tokens, len) = tokenize(input)?;
+    parse_tokens(&tokens, len)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng, rngs::StdRng, seq::SliceRandom};
+
+    use super::parse;
+    use crate::{
+        circuit::{Block, Circuit},
+        helpers::{abs, app, gate, ket, meas, nonlinear, nonlinear_abs, rotation, tuple, var},
+        term::{Const, GATES, Rotation, Term},
+    };
+
+    // Names for variables, including the ones sugar is parsed with, ones that must be quoted and
+    // ones that look like keywords, gates or numbers.
+    const NAMES: &[&str] = &[
+        "x", "y", "b", "b'", "c", "n", "x1", "let", "in", "true", "H", "Rz", "M", "my var", "a`b",
+        "--", "(", "0", "|0>", "",
+    ];
+
+    const ANGLES: &[f64] = &[0.0, 1.0, -0.5, 0.785, std::f64::consts::PI, 1e-7, 2.5e10];
+
+    fn name(rng: &mut StdRng) -> &'static str {
+        NAMES.choose(rng).unwrap()
+    }
+
+    fn angle(rng: &mut StdRng) -> f64 {
+        if rng.gen_bool(0.5) {
+            *ANGLES.choose(rng).unwrap()
+        } else {
+            rng.gen_range(-10.0..10.0)
+        }
     }
 
-    if res.len() == 1 {
-        Ok(res.into_iter().next().unwrap())
-    } else {
-        match res.into_iter().reduce(app) {
-            Some(res) => Ok(res),
-            None => Err(ParseError::EmptyList),
+    fn rotation_kind(rng: &mut StdRng) -> Rotation {
+        *[Rotation::Rx, Rotation::Ry, Rotation::Rz, Rotation::Phase]
+            .choose(rng)
+            .unwrap()
+    }
+
+    // Generates a random term of at most the given depth, with a bias towards the shapes that
+    // are printed with sugar: booleans, tuples and lists, applied or not.
+    fn term(rng: &mut StdRng, depth: usize) -> Term {
+        let leaf = depth == 0 || rng.gen_bool(0.25);
+        if leaf {
+            return match rng.gen_range(0..7) {
+                0 | 1 => var(name(rng)),
+                2 => ket(rng.gen_bool(0.5)),
+                3 => gate(GATES.choose(rng).unwrap()),
+                4 => rotation(rotation_kind(rng), angle(rng)),
+                5 => meas(),
+                _ => Term::Const(Const::Qubit(rng.gen_range(0..4))),
+            };
+        }
+
+        let depth = depth - 1;
+        match rng.gen_range(0..9) {
+            0 => abs(name(rng), term(rng, depth)),
+            1 => nonlinear_abs(name(rng), term(rng, depth)),
+            2 => nonlinear(term(rng, depth)),
+            3 => app(term(rng, depth), term(rng, depth)),
+            4 => {
+                let (x, y) = (name(rng), name(rng));
+                let body = var(if rng.gen_bool(0.5) { x } else { y });
+                nonlinear_abs(x, nonlinear_abs(y, body))
+            }
+            5 => {
+                let n = rng.gen_range(2..5);
+                tuple((0..n).map(|_| term(rng, depth)).collect())
+            }
+            6 => {
+                let n = rng.gen_range(0..4);
+                let elems: Vec<Term> = (0..n).map(|_| term(rng, depth)).collect();
+                list(rng, elems)
+            }
+            // An abstraction over a tuple or list of its variables, applied to some arguments,
+            // as tuples and lists of terms which aren't values are parsed
+            7 => {
+                let xs: Vec<&str> = (0..rng.gen_range(1..4)).map(|_| name(rng)).collect();
+                let elems = xs.iter().map(|x| var(x)).collect();
+                let body = if rng.gen_bool(0.5) {
+                    tuple(elems)
+                } else {
+                    list(rng, elems)
+                };
+                let f = xs.iter().rev().fold(body, |t, x| abs(x, t));
+                xs.iter().fold(f, |t, _| app(t, term(rng, depth)))
+            }
+            _ => {
+                let (head, elem) = (term(rng, depth), term(rng, depth));
+                tuple(vec![head, list(rng, vec![elem])])
+            }
         }
     }
-}
 
-pub fn parse(input: &mut Chars) -> Result<Term, ParseError> {
-    let tokens = tokenize(input);
-    parse_tokens(&tokens)
+    // Builds a list from pairs \b.b head tail ending in #x.#y.y, with names picked at random so
+    // that they sometimes clash with the elements and sometimes are the ones the parser picks.
+    fn list(rng: &mut StdRng, elems: Vec<Term>) -> Term {
+        let (x, y) = (name(rng), name(rng));
+        let nil = nonlinear_abs(x, nonlinear_abs(y, var(y)));
+        elems.into_iter().rev().fold(nil, |tail, head| {
+            let b = name(rng);
+            abs(b, app(app(var(b), head), tail))
+        })
+    }
+
+    // Generates a random circuit on up to four wires.
+    fn circuit(rng: &mut StdRng) -> Circuit {
+        let width = rng.gen_range(1..5);
+        let input = (0..width).map(|_| rng.gen_bool(0.5)).collect();
+        let layers = (0..rng.gen_range(1..4))
+            .map(|_| {
+                let mut layer = Vec::new();
+                let mut used = 0;
+                while used < width {
+                    let block = match rng.gen_range(0..8) {
+                        0 => Block::I,
+                        1 => Block::H,
+                        2 => Block::T,
+                        3 => Block::X,
+                        4 => Block::Z,
+                        5 => Block::S,
+                        6 if used + 2 <= width => *[Block::C, Block::Swap].choose(rng).unwrap(),
+                        _ => Block::Rot(rotation_kind(rng), angle(rng)),
+                    };
+                    used += block.width();
+                    layer.push(block);
+                }
+                layer
+            })
+            .collect();
+        Circuit {
+            layers,
+            input,
+            measure: rng.gen_bool(0.5),
+        }
+    }
+
+    fn assert_round_trips(t: &Term) {
+        let printed = t.to_string();
+        let parsed = parse(&mut printed.chars())
+            .unwrap_or_else(|e| panic!("{printed} failed to parse: {e:?}, from {t:?}"));
+        assert_eq!(parsed, *t, "{printed} parsed as {parsed:?}, not {t:?}");
+    }
+
+    #[test]
+    fn printed_terms_parse_back() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..5000 {
+            assert_round_trips(&term(&mut rng, 5));
+        }
+    }
+
+    #[test]
+    fn printed_circuits_parse_back() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..500 {
+            assert_round_trips(&circuit(&mut rng).to_lambda().unwrap());
+        }
+    }
+
+    #[test]
+    fn sugar_parses_back() {
+        for source in [
+            "`my var` `let` (\\`in`. `in`)",
+            "Rz(0.785) (Phase(-1.5) |0>)",
+            "Rx(3.141592653589793) |1>",
+            "!(H |0>) !(\\x. x)",
+            "#x. (x, x)",
+            "(true, false)",
+            "(|0>, false)",
+            "(|1>, [|0>])",
+            "[|0>, |1>, true]",
+            "[]",
+            "\\n. [n, |0>]",
+            "(M x, y)",
+            "[H |0>, (|0>, |1>)]",
+            "let (a, b) = p in (b, a)",
+        ] {
+            let t = parse(&mut source.chars()).unwrap();
+            assert_round_trips(&t);
+        }
+    }
+
+    #[test]
+    fn lists_are_pairs_ending_in_false() {
+        let parsed = |source: &str| parse(&mut source.chars()).unwrap();
+        assert_eq!(parsed("[|1>, |0>]"), parsed("(|1>, (|0>, false))"));
+        assert_eq!(parsed("(|0>, false)").to_string(), "[|0>]");
+        assert_eq!(parsed("(|1>, [|0>])").to_string(), "[|1>, |0>]");
+    }
 }
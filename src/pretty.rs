@@ -0,0 +1,333 @@
+use crate::{
+    parser::identifier,
+    superpos::{Mixture, Superpos},
+    term::{Position, Term, Value},
+};
+use num_complex::Complex;
+
+// The widest a line of output may be before it is broken over several lines.
+pub const WIDTH: usize = 100;
+
+// How close a number must be to an exact value to be printed as it.
+const TOLERANCE: f64 = 1e-9;
+
+// The largest denominator tried when recognising a number as a fraction.
+const MAX_DENOMINATOR: u64 = 64;
+
+// The largest power of two tried as a denominator beyond those.
+const MAX_POWER: u32 = 24;
+
+// How deep a term is broken over lines before its subterms are printed on a single line, however
+// long, which keeps the layout from overflowing the call stack.
+const MAX_DEPTH: usize = 64;
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+// Splits a number n into a and b such that n = a^2 b, with b squarefree.
+fn squarefree(mut n: u64) -> (u64, u64) {
+    let mut a = 1;
+    let mut k = 2;
+    while k * k <= n {
+        while n.is_multiple_of(k * k) {
+            n /= k * k;
+            a *= k;
+        }
+        k += 1;
+    }
+    (a, n)
+}
+
+// Recognises a nonnegative number as a fraction p/q in lowest terms with a small denominator, or
+// one that is a power of two, as the squared amplitudes of a register under Hadamards have.
+fn fraction(x: f64) -> Option<(u64, u64)> {
+    let powers = (7..=MAX_POWER).map(|k| 1 << k);
+    (1..=MAX_DENOMINATOR).chain(powers).find_map(|q| {
+        let p = (x * q as f64).round();
+        let close = (p / q as f64 - x).abs() < TOLERANCE && (p - x * q as f64).abs() < 1e-6;
+        (close && p >= 0.0).then(|| {
+            let p = p as u64;
+            let d = gcd(p, q);
+            (p / d, q / d)
+        })
+    })
+}
+
+// Prints a number with at most three decimal places, or in scientific notation if it is too small
+// to show that way.
+fn decimal(x: f64) -> String {
+    if x != 0.0 && x.abs() < 1e-3 {
+        return format!("{x:.3e}");
+    }
+    let s = format!("{x:.3}");
+    let s = s.trim_end_matches('0').trim_end_matches('.');
+    if s == "-0" { "0".into() } else { s.into() }
+}
+
+// Prints a probability as a fraction if it is one, or else as a decimal.
+pub fn probability(p: f64) -> String {
+    match fraction(p) {
+        Some((p, 1)) => p.to_string(),
+        Some((p, q)) => format!("{p}/{q}"),
+        None => decimal(p),
+    }
+}
+
+// Prints a nonnegative magnitude exactly when its square is a fraction with a small denominator,
+// as in 1/√2 or √3/2.
+fn magnitude(r: f64) -> Option<String> {
+    let (p, q) = fraction(r * r)?;
+    let (a, p) = squarefree(p);
+    let (b, q) = squarefree(q);
+    let num = match (a, p) {
+        (a, 1) => a.to_string(),
+        (1, p) => format!("√{p}"),
+        (a, p) => format!("{a}√{p}"),
+    };
+    let den = match (b, q) {
+        (1, 1) => String::new(),
+        (b, 1) => format!("/{b}"),
+        (1, q) => format!("/√{q}"),
+        (b, q) => format!("/({b}√{q})"),
+    };
+    Some(num + &den)
+}
+
+// Recognises an angle as a fraction m/n of π in lowest terms, with -n < m <= n.
+fn phase(theta: f64) -> Option<(i64, i64)> {
+    let turns = theta / std::f64::consts::PI;
+    (1..=16).find_map(|n| {
+        let m = (turns * n as f64).round();
+        ((m / n as f64 - turns).abs() < TOLERANCE).then(|| {
+            let m = m as i64;
+            let d = gcd(m.unsigned_abs(), n as u64) as i64;
+            (m / d, n / d)
+        })
+    })
+}
+
+// Prints an amplitude, simplified to forms like 1/√2 or e^{iπ/4}/√2 where it can be, giving
+// whether it is negative separately so that it can be subtracted in a sum.
+pub fn amplitude(c: Complex<f64>) -> (bool, String) {
+    let exact = magnitude(c.norm()).zip(phase(c.arg()));
+    let Some((mag, (m, n))) = exact else {
+        return match (c.re.abs() < TOLERANCE, c.im.abs() < TOLERANCE) {
+            (_, true) => (c.re < 0.0, decimal(c.re.abs())),
+            (true, false) => (c.im < 0.0, format!("{}i", decimal(c.im.abs()))),
+            (false, false) => {
+                let sign = if c.im < 0.0 { '-' } else { '+' };
+                let im = decimal(c.im.abs());
+                (false, format!("({}{sign}{im}i)", decimal(c.re)))
+            }
+        };
+    };
+
+    // A phase of ±π is a negative sign, and one of -π/2 a negative i.
+    let (negative, m, n) = match (m, n) {
+        (1, 1) | (-1, 1) => (true, 0, 1),
+        (-1, 2) => (true, 1, 2),
+        (m, n) => (false, m, n),
+    };
+    let factor = match (m, n) {
+        (0, _) => return (negative, mag),
+        (1, 2) => "i".to_string(),
+        (1, n) => format!("e^{{iπ/{n}}}"),
+        (-1, n) => format!("e^{{-iπ/{n}}}"),
+        (m, n) => format!("e^{{{m}iπ/{n}}}"),
+    };
+    let text = match mag.strip_prefix('1') {
+        Some("") => factor,
+        Some(den) if den.starts_with('/') => factor + den,
+        _ if factor == "i" => format!("i{mag}"),
+        _ => format!("{mag}·{factor}"),
+    };
+    (negative, text)
+}
+
+// Labels the basis state of a ket or tuple of kets by its bits, as in |01>.
+pub(crate) fn ket_label(t: &Term) -> Option<String> {
+    let bits: String = t
+        .as_basis()?
+        .into_iter()
+        .map(|b| if b { '1' } else { '0' })
+        .collect();
+    Some(format!("|{bits}>"))
+}
+
+// Joins the items of a collection between brackets on one line if it fits in the width, or else
+// puts each on its own line.
+fn lines(open: &str, items: Vec<String>, close: &str, width: usize) -> String {
+    let flat = format!("{open}{}{close}", items.join(", "));
+    if flat.chars().count() <= width && !flat.contains('\n') {
+        return flat;
+    }
+    let items: String = items
+        .iter()
+        .map(|item| format!("  {},\n", item.replace('\n', "\n  ")))
+        .collect();
+    format!("{open}\n{items}{close}")
+}
+
+// Prints a superposition in Dirac notation, as in 1/√2|00> - 1/√2|11>, with each branch on its own
+// line if they do not fit on one.
+pub fn superpos(s: &Superpos, width: usize) -> String {
+    let branches: Vec<(bool, String)> =
+        s.0.iter()
+            .map(|(t, amp)| {
+                let (negative, amp) = amplitude(*amp);
+                let branch = match (ket_label(t), amp.as_str()) {
+                    (Some(label), "1") => label,
+                    (Some(label), _) => amp + &label,
+                    (None, "1") => term(t, width),
+                    (None, _) => format!("{amp} {}", parenthesized(t, width)),
+                };
+                (negative, branch)
+            })
+            .collect();
+
+    let mut flat = String::new();
+    for (i, (negative, branch)) in branches.iter().enumerate() {
+        flat += match (i, negative) {
+            (0, false) => "",
+            (0, true) => "-",
+            (_, false) => " + ",
+            (_, true) => " - ",
+        };
+        flat += branch;
+    }
+    if branches.is_empty() {
+        return "0".into();
+    }
+    if flat.chars().count() <= width && !flat.contains('\n') {
+        return flat;
+    }
+
+    let mut out = String::new();
+    for (i, (negative, branch)) in branches.iter().enumerate() {
+        let sign = match (i, negative) {
+            (0, false) => "  ",
+            (_, false) => "+ ",
+            (_, true) => "- ",
+        };
+        let branch = branch.replace('\n', "\n  ");
+        out += &format!("{}{sign}{branch}", if i == 0 { "" } else { "\n" });
+    }
+    out
+}
+
+// Prints a mixture as its states along with their probabilities.
+pub fn mixture(m: &Mixture, width: usize) -> String {
+    let items =
+        m.0.iter()
+            .map(|(v, p)| format!("{}: {}", probability(*p), value(v, width)))
+            .collect();
+    lines("{", items, "}", width)
+}
+
+// Prints a value, breaking terms and superpositions over several lines where they would be wider
+// than the width.
+pub fn value(v: &Value, width: usize) -> String {
+    match v {
+        Value::Term(t) => term(t, width),
+        Value::Superpos(s) => superpos(s, width),
+        Value::Mixed(m) => mixture(m, width),
+    }
+}
+
+// Prints a term in parentheses unless it is atomic, as for a branch of a superposition.
+fn parenthesized(t: &Term, width: usize) -> String {
+    if t.needs_parens(Position::Arg) || t.needs_parens(Position::Func) {
+        format!(
+            "({})",
+            term(t, width.saturating_sub(2)).replace('\n', "\n ")
+        )
+    } else {
+        term(t, width)
+    }
+}
+
+// Prints a term, breaking it over several lines where it would be wider than the width.
+pub fn term(t: &Term, width: usize) -> String {
+    let mut out = String::new();
+    layout(t, Position::Open, 0, width, 0, &mut out);
+    out
+}
+
+// Lays out a term at the given position, starting at the given column, by printing it on a single
+// line if it fits and otherwise breaking it up: an application puts each argument on its own
+// indented line, an abstraction its body, and a tuple or list each element.
+fn layout(t: &Term, pos: Position, indent: usize, width: usize, depth: usize, out: &mut String) {
+    let flat = t.to_string();
+    let parens = t.needs_parens(pos);
+    let len = flat.chars().count() + if parens { 2 } else { 0 };
+    if indent + len <= width || depth >= MAX_DEPTH {
+        if parens {
+            out.push('(');
+        }
+        out.push_str(&flat);
+        if parens {
+            out.push(')');
+        }
+        return;
+    }
+
+    let newline = |out: &mut String, indent: usize| {
+        out.push('\n');
+        out.push_str(&" ".repeat(indent));
+    };
+    let (indent, close) = if parens {
+        out.push('(');
+        (indent + 1, ")")
+    } else {
+        (indent, "")
+    };
+
+    if let Some((open, elems, end)) = t.as_sugared() {
+        out.push_str(open);
+        for (i, elem) in elems.iter().enumerate() {
+            newline(out, indent + 2);
+            layout(elem, Position::Open, indent + 2, width, depth + 1, out);
+            if i + 1 < elems.len() {
+                out.push(',');
+            }
+        }
+        newline(out, indent);
+        out.push_str(end);
+    } else {
+        match t {
+            Term::App(_, _) => {
+                let mut args = Vec::new();
+                let mut head = t;
+                while let Term::App(f, a) = head {
+                    args.push(&**a);
+                    head = f;
+                }
+                layout(head, Position::Func, indent, width, depth + 1, out);
+                for arg in args.into_iter().rev() {
+                    newline(out, indent + 2);
+                    layout(arg, Position::Arg, indent + 2, width, depth + 1, out);
+                }
+            }
+            Term::Abs(_, _) | Term::NonlinearAbs(_, _) if t.as_bool().is_none() => {
+                let (xs, body) = t.binders();
+                let lam = if matches!(t, Term::Abs(_, _)) {
+                    "λ"
+                } else {
+                    "#"
+                };
+                let xs: Vec<_> = xs.into_iter().map(identifier).collect();
+                out.push_str(&format!("{lam}{}.", xs.join(" ")));
+                newline(out, indent + 2);
+                layout(body, Position::Open, indent + 2, width, depth + 1, out);
+            }
+            Term::Nonlinear(inner) => {
+                out.push_str("!(");
+                layout(inner, Position::Open, indent + 2, width, depth + 1, out);
+                out.push(')');
+            }
+            _ => out.push_str(&flat),
+        }
+    }
+    out.push_str(close);
+}
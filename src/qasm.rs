@@ -0,0 +1,423 @@
+use crate::circuit::{Block, Circuit};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone)]
+pub enum QasmError {
+    // A statement that isn't well formed, on the given line.
+    Syntax(usize, String),
+    // A gate or statement with no counterpart in a circuit, on the given line.
+    Unsupported(usize, String),
+    // A program declaring a version of OpenQASM other than 2 or 3, on the given line.
+    Version(usize, String),
+    // A register that wasn't declared, or an index past the end of one.
+    UnknownQubit(usize, String),
+    // A gate applied to a qubit after it has been measured.
+    AfterMeasure(usize, String),
+    // A measurement of some qubits but not all, which a circuit can't express.
+    PartialMeasure(usize),
+    // A program without any qubits.
+    NoQubits,
+}
+
+impl std::fmt::Display for QasmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QasmError::Syntax(line, msg) => write!(f, "line {line}: {msg}"),
+            QasmError::Unsupported(line, name) => {
+                write!(f, "line {line}: unsupported gate or statement `{name}`")
+            }
+            QasmError::Version(line, version) => write!(
+                f,
+                "line {line}: OpenQASM {version} is not supported, only OpenQASM 2 and 3"
+            ),
+            QasmError::UnknownQubit(line, arg) => write!(f, "line {line}: unknown qubit `{arg}`"),
+            QasmError::AfterMeasure(line, arg) => {
+                write!(f, "line {line}: `{arg}` is used after it has been measured")
+            }
+            QasmError::PartialMeasure(line) => write!(
+                f,
+                "line {line}: only some qubits are measured, but a circuit can only measure them all at the end"
+            ),
+            QasmError::NoQubits => write!(f, "no qubits are declared"),
+        }
+    }
+}
+
+// Builds up the layers of a circuit one gate at a time, putting each gate in the last layer if its
+// wires are still free there and otherwise starting a new one.
+struct Builder {
+    layers: Vec<Vec<Block>>,
+    // The blocks of the layer being filled, by the first wire they act on.
+    blocks: Vec<(usize, Block)>,
+    busy: Vec<bool>,
+}
+
+impl Builder {
+    fn new(qubits: usize) -> Self {
+        Builder {
+            layers: Vec::new(),
+            blocks: Vec::new(),
+            busy: vec![false; qubits],
+        }
+    }
+
+    // Ends the current layer, filling the wires without a gate with identities.
+    fn flush(&mut self) {
+        if self.blocks.is_empty() {
+            return;
+        }
+        let mut layer = Vec::new();
+        let mut wire = 0;
+        while wire < self.busy.len() {
+            match self.blocks.iter().find(|(w, _)| *w == wire) {
+                Some((_, block)) => {
+                    layer.push(*block);
+                    wire += block.width();
+                }
+                None => {
+                    layer.push(Block::I);
+                    wire += 1;
+                }
+            }
+        }
+        self.layers.push(layer);
+        self.blocks.clear();
+        self.busy.iter_mut().for_each(|b| *b = false);
+    }
+
+    fn place(&mut self, wire: usize, block: Block) {
+        let wires = wire..wire + block.width();
+        if self.busy[wires.clone()].iter().any(|b| *b) {
+            self.flush();
+        }
+        self.busy[wires].iter_mut().for_each(|b| *b = true);
+        self.blocks.push((wire, block));
+    }
+
+    // Applies a CNOT between any two wires. A CNOT block only acts on a wire and the one below
+    // it, so the target is swapped along the wires until it sits just below the control, and
+    // swapped back afterwards.
+    fn cnot(&mut self, control: usize, target: usize) {
+        let (swaps, at): (Vec<usize>, usize) = if target > control {
+            ((control + 1..target).rev().collect(), control)
+        } else {
+            ((target..control).collect(), control - 1)
+        };
+        for &w in &swaps {
+            self.place(w, Block::Swap);
+        }
+        self.place(at, Block::C);
+        for &w in swaps.iter().rev() {
+            self.place(w, Block::Swap);
+        }
+    }
+
+    // Swaps any two wires, by moving the upper one down to the lower and then the lower one,
+    // which has been moved up by a wire, back to where the upper one was.
+    fn swap(&mut self, a: usize, b: usize) {
+        let (a, b) = (a.min(b), a.max(b));
+        for w in (a..b).chain((a..b - 1).rev()) {
+            self.place(w, Block::Swap);
+        }
+    }
+}
+
+// Splits a program into its statements, ended by semicolons, along with the line each starts on.
+fn statements(src: &str) -> Result<Vec<(usize, String)>, QasmError> {
+    let mut stmts = Vec::new();
+    let mut cur = String::new();
+    let mut start = 0;
+    for (i, line) in src.lines().enumerate() {
+        let line = line.split("//").next().unwrap_or_default();
+        for c in line.chars() {
+            if cur.trim().is_empty() {
+                start = i + 1;
+            }
+            if c == ';' {
+                stmts.push((start, cur.trim().to_string()));
+                cur.clear();
+            } else {
+                cur.push(c);
+            }
+        }
+        cur.push('\n');
+    }
+    match cur.trim() {
+        "" => Ok(stmts),
+        _ => Err(QasmError::Syntax(start, "missing `;`".into())),
+    }
+}
+
+// Parses a register declaration like q[2] into its name and size.
+fn declaration(line: usize, decl: &str) -> Result<(String, usize), QasmError> {
+    let bad = || QasmError::Syntax(line, format!("bad register declaration `{decl}`"));
+    let (name, size) = decl
+        .strip_suffix(']')
+        .ok_or_else(bad)?
+        .split_once('[')
+        .ok_or_else(bad)?;
+    let size = size.trim().parse().map_err(|_| bad())?;
+    Ok((name.trim().to_string(), size))
+}
+
+// Parses an OpenQASM 3 declaration following qubit or bit, like [2] q, or q for a single qubit or
+// bit, into its name and size.
+fn typed_declaration(line: usize, decl: &str) -> Result<(String, usize), QasmError> {
+    let bad = || QasmError::Syntax(line, format!("bad register declaration `{decl}`"));
+    let (size, name) = match decl.trim().strip_prefix('[') {
+        Some(rest) => {
+            let (size, name) = rest.split_once(']').ok_or_else(bad)?;
+            (size.trim().parse().map_err(|_| bad())?, name.trim())
+        }
+        None => (1, decl.trim()),
+    };
+    if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return Err(bad());
+    }
+    Ok((name.to_string(), size))
+}
+
+// Splits a statement into the name it starts with and the rest of it.
+fn split_name(stmt: &str) -> (&str, &str) {
+    let end = stmt
+        .find(|c: char| !c.is_alphanumeric() && c != '_')
+        .unwrap_or(stmt.len());
+    stmt.split_at(end)
+}
+
+// Gets the qubits an argument like q[1] or q refers to, where a whole register stands for each
+// of its qubits in turn.
+fn qubits(
+    line: usize,
+    regs: &HashMap<String, (usize, usize)>,
+    arg: &str,
+) -> Result<Vec<usize>, QasmError> {
+    let unknown = || QasmError::UnknownQubit(line, arg.to_string());
+    let (name, index) = match arg.strip_suffix(']').and_then(|a| a.split_once('[')) {
+        Some((name, index)) => (name.trim(), Some(index.trim())),
+        None => (arg, None),
+    };
+    let (offset, size) = *regs.get(name).ok_or_else(unknown)?;
+    match index {
+        None => Ok((offset..offset + size).collect()),
+        Some(i) => match i.parse::<usize>() {
+            Ok(i) if i < size => Ok(vec![offset + i]),
+            _ => Err(unknown()),
+        },
+    }
+}
+
+// Lines the qubits of the arguments of a gate up into the sets it is applied to, where registers
+// must all be of one size and a single qubit goes with every qubit of them.
+fn broadcast(line: usize, args: &[Vec<usize>]) -> Result<Vec<Vec<usize>>, QasmError> {
+    let n = args.iter().map(Vec::len).max().unwrap_or(0);
+    if args.iter().any(|a| a.len() != 1 && a.len() != n) {
+        return Err(QasmError::Syntax(
+            line,
+            "registers of different sizes".into(),
+        ));
+    }
+    Ok((0..n)
+        .map(|i| args.iter().map(|a| a[i % a.len()]).collect())
+        .collect())
+}
+
+// Records a measurement of the qubits q into the bits c, which must be as many, marking each
+// qubit with the line it was first measured on.
+fn measure(
+    line: usize,
+    qregs: &HashMap<String, (usize, usize)>,
+    cregs: &HashMap<String, (usize, usize)>,
+    q: &str,
+    c: &str,
+    measured: &mut [Option<usize>],
+) -> Result<(), QasmError> {
+    let qs = qubits(line, qregs, q.trim())?;
+    let cs = qubits(line, cregs, c.trim())?;
+    if qs.len() != cs.len() {
+        return Err(QasmError::Syntax(
+            line,
+            "registers of different sizes".into(),
+        ));
+    }
+    for q in qs {
+        measured[q].get_or_insert(line);
+    }
+    Ok(())
+}
+
+// Parses an OpenQASM 2 or 3 program into a circuit. The qubits of every qreg, or qubit
+// declaration in OpenQASM 3, are the wires of the circuit, in the order they are declared, and
+// all start as |0>. The gates h, t, x, z, s, cx and swap are supported, along with barriers,
+// which are ignored, and measurements, written measure q -> c or c = measure q, so long as every
+// qubit is measured and nothing is applied to a qubit after it has been.
+pub fn parse_qasm(src: &str) -> Result<Circuit, QasmError> {
+    let stmts = statements(src)?;
+
+    // Registers are declared before they are used, so the wires are known before any gate.
+    let mut qregs = HashMap::new();
+    let mut cregs = HashMap::new();
+    let mut width = 0;
+    let mut bits = 0;
+    for (line, stmt) in &stmts {
+        let (keyword, decl) = split_name(stmt);
+        let (name, size) = match keyword {
+            "qreg" | "creg" => declaration(*line, decl)?,
+            "qubit" | "bit" => typed_declaration(*line, decl)?,
+            "OPENQASM" => match decl.trim().split('.').next() {
+                Some("2" | "3") => continue,
+                _ => return Err(QasmError::Version(*line, decl.trim().into())),
+            },
+            _ => continue,
+        };
+        if matches!(keyword, "qreg" | "qubit") {
+            qregs.insert(name, (width, size));
+            width += size;
+        } else {
+            cregs.insert(name, (bits, size));
+            bits += size;
+        }
+    }
+    if width == 0 {
+        return Err(QasmError::NoQubits);
+    }
+
+    let mut builder = Builder::new(width);
+    let mut measured: Vec<Option<usize>> = vec![None; width];
+    for (line, stmt) in &stmts {
+        let line = *line;
+        let (name, rest) = split_name(stmt);
+        match name {
+            "OPENQASM" | "include" | "qreg" | "creg" | "qubit" | "bit" | "barrier" => continue,
+            // A measurement assigned to bits, as OpenQASM 3 writes c = measure q
+            _ if rest.contains('=') => {
+                let (c, value) = stmt.split_once('=').expect("the statement has an `=`");
+                let ("measure", q) = split_name(value.trim()) else {
+                    return Err(QasmError::Unsupported(line, stmt.clone()));
+                };
+                measure(line, &qregs, &cregs, q, c, &mut measured)?;
+                continue;
+            }
+            _ if rest.trim_start().starts_with('(') => {
+                return Err(QasmError::Unsupported(line, name.into()));
+            }
+            "measure" => {
+                let (q, c) = rest
+                    .split_once("->")
+                    .ok_or_else(|| QasmError::Syntax(line, "measure needs `->`".into()))?;
+                measure(line, &qregs, &cregs, q, c, &mut measured)?;
+                continue;
+            }
+            _ => (),
+        }
+
+        let args: Vec<&str> = rest.split(',').map(str::trim).collect();
+        let arity = match name {
+            "h" | "t" | "x" | "z" | "s" => 1,
+            "cx" | "CX" | "swap" => 2,
+            _ => return Err(QasmError::Unsupported(line, name.into())),
+        };
+        if args.len() != arity || args.iter().any(|a| a.is_empty()) {
+            let msg = format!("{name} takes {arity} qubit argument(s)");
+            return Err(QasmError::Syntax(line, msg));
+        }
+        let args = args
+            .iter()
+            .map(|a| qubits(line, &qregs, a))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for qs in broadcast(line, &args)? {
+            if let Some(&q) = qs.iter().find(|q| measured[**q].is_some()) {
+                let (reg, (offset, _)) = qregs
+                    .iter()
+                    .find(|(_, (offset, size))| (*offset..offset + size).contains(&q))
+                    .expect("every qubit is in a register");
+                return Err(QasmError::AfterMeasure(
+                    line,
+                    format!("{reg}[{}]", q - offset),
+                ));
+            }
+            match (name, qs.as_slice()) {
+                ("h", [q]) => builder.place(*q, Block::H),
+                ("t", [q]) => builder.place(*q, Block::T),
+                ("x", [q]) => builder.place(*q, Block::X),
+                ("z", [q]) => builder.place(*q, Block::Z),
+                ("s", [q]) => builder.place(*q, Block::S),
+                (_, [a, b]) if a == b => {
+                    let msg = format!("{name} is applied to the same qubit twice");
+                    return Err(QasmError::Syntax(line, msg));
+                }
+                ("cx" | "CX", [c, t]) => builder.cnot(*c, *t),
+                ("swap", [a, b]) => builder.swap(*a, *b),
+                _ => unreachable!("the arity of each gate has been checked"),
+            }
+        }
+    }
+    builder.flush();
+
+    let measure = match measured.iter().find_map(|m| *m) {
+        None => false,
+        Some(_) if measured.iter().all(Option::is_some) => true,
+        Some(line) => return Err(QasmError::PartialMeasure(line)),
+    };
+    Ok(Circuit {
+        layers: builder.layers,
+        input: vec![false; width],
+        measure,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{QasmError, parse_qasm};
+
+    const V2: &str = "OPENQASM 2.0;
+include \"qelib1.inc\";
+qreg q[2];
+creg c[2];
+h q[0];
+cx q[0], q[1];
+measure q -> c;
+";
+
+    #[test]
+    fn openqasm_3_reads_as_openqasm_2() {
+        let expected = parse_qasm(V2).unwrap();
+        for src in [
+            "OPENQASM 3.0;
+include \"stdgates.inc\";
+qubit[2] q;
+bit[2] c;
+h q[0];
+cx q[0], q[1];
+c = measure q;
+",
+            "OPENQASM 3;
+qubit a;
+qubit b;
+bit[2] c;
+h a;
+cx a, b;
+c[0] = measure a;
+c[1] = measure b;
+",
+        ] {
+            let circuit = parse_qasm(src).unwrap();
+            assert_eq!(circuit.layers, expected.layers);
+            assert_eq!(circuit.input, expected.input);
+            assert_eq!(circuit.measure, expected.measure);
+        }
+    }
+
+    #[test]
+    fn other_versions_and_assignments_are_reported() {
+        assert!(matches!(
+            parse_qasm("OPENQASM 4.0;\nqubit q;"),
+            Err(QasmError::Version(1, _))
+        ));
+        assert!(matches!(
+            parse_qasm("OPENQASM 3.0;\nqubit q;\nbit c;\nc = q;"),
+            Err(QasmError::Unsupported(4, _))
+        ));
+    }
+}
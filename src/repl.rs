@@ -1,14 +1,47 @@
 use crate::{
-    helpers::{abs, app, nonlinear, nonlinear_abs},
-    parser::parse,
-    term::{Term, eval},
+    export::{Format, export, json, json_errors},
+    helpers::{abs, app, gate, nonlinear, nonlinear_abs},
+    histogram::Histogram,
+    module::items,
+    parser::{parse, parse_spanned, strip_comment},
+    span::Span,
+    term::{
+        Backend, Const, Engine, EvalError, Evaluator, GATES, Measurement, Node, Strategy, Term,
+    },
+    unitary::parse_unitary,
 };
 use rustyline::{DefaultEditor, Result, error::ReadlineError};
-use std::{collections::HashMap, fs::File, process::exit};
-use std::{io::Read, path::Path};
+use std::path::Path;
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    process::exit,
+    rc::Rc,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+// Where the expression being read comes from within a file, so that diagnostics can point into
+// the file rather than the expression alone.
+#[derive(Clone)]
+struct Source {
+    path: Rc<str>,
+    text: Rc<str>,
+    // The offset in characters of the expression from the start of the file.
+    offset: usize,
+}
 
 pub struct Repl {
     env: HashMap<String, Term>,
+    evaluator: Evaluator,
+    // The file the input is being read from, if any.
+    source: Option<Source>,
+    // The modules being loaded, innermost last, to catch modules importing each other in a cycle.
+    loading: Vec<PathBuf>,
+    // The errors reported so far, each with the place it was found, so that running a module can
+    // tell whether it failed.
+    errors: Vec<String>,
 }
 
 impl Default for Repl {
@@ -20,8 +53,17 @@ impl Default for Repl {
 impl Repl {
     // Creates a new Repl with an empty environment.
     pub fn new() -> Self {
+        Self::with_evaluator(Evaluator::default())
+    }
+
+    // Creates a new Repl with an empty environment which evaluates terms with the given evaluator.
+    pub fn with_evaluator(evaluator: Evaluator) -> Self {
         Self {
             env: HashMap::new(),
+            evaluator,
+            source: None,
+            loading: Vec::new(),
+            errors: Vec::new(),
         }
     }
 
@@ -47,8 +89,344 @@ impl Repl {
         self.env.clear();
     }
 
+    // Parses the expression and substitutes the environment into it, along with the spans of the
+    // subterms of the result in pre-order. Subterms substituted from the environment take the span
+    // of the symbol they replaced. Parse errors are reported, pointing into the expression.
+    fn parse_populated(&mut self, expr: &str) -> Option<(Term, Vec<Span>)> {
+        match parse_spanned(&mut expr.chars()) {
+            Ok((t, spans)) => {
+                let spans = populate_spans(&t, &spans, &self.env);
+                let mut t = populate_term(t, &self.env);
+                mark_sites(&mut t);
+                Some((t, spans))
+            }
+            Err(e) => {
+                self.fail(format!("Parser Error: {e}"), Some((e.span(), expr)));
+                None
+            }
+        }
+    }
+
+    // Renders a span of the expression being read, pointing into the file it comes from if any.
+    fn render(&self, span: Span, expr: &str) -> String {
+        match &self.source {
+            Some(source) => {
+                let span = span.shift(source.offset);
+                let (line, col) = span.position(&source.text);
+                let lines = span.render(&source.text);
+                format!("--> {}:{line}:{col}\n{lines}", source.path)
+            }
+            None => span.render(expr),
+        }
+    }
+
+    // Reports an error on stderr, pointing at a span of the expression being read if it has one,
+    // and keeps it along with the place it was found.
+    fn fail(&mut self, message: String, at: Option<(Span, &str)>) {
+        let place = match (&self.source, at) {
+            (Some(source), Some((span, _))) => {
+                let (line, col) = span.shift(source.offset).position(&source.text);
+                format!("{}:{line}:{col}: ", source.path)
+            }
+            (Some(source), None) => format!("{}: ", source.path),
+            (None, _) => String::new(),
+        };
+        match at {
+            Some((span, expr)) => eprint!("{message}\n{}", self.render(span, expr)),
+            None => eprintln!("{message}"),
+        }
+        self.errors.push(place + &message);
+    }
+
+    // Reports an evaluation error, showing how far the evaluation got if it was stopped early, and
+    // pointing into the evaluated expression when the error is located in it, given the spans of
+    // its subterms in pre-order.
+    fn report(&mut self, e: EvalError, expr: &str, spans: &[Span]) {
+        let (message, span) = match e {
+            EvalError::OutOfFuel(v) => (
+                format!("Evaluation Error: out of fuel after reducing to\n{v}"),
+                None,
+            ),
+            EvalError::Interrupted(v) => (format!("Interrupted after reducing to\n{v}"), None),
+            EvalError::TypeError(e) => (format!("Type Error: {e}"), spans.get(e.node()).copied()),
+            EvalError::LinearityViolation(e, Some(at)) => {
+                (format!("Evaluation Error: {e}"), spans.get(at).copied())
+            }
+            EvalError::BadGate(e, site) => (
+                format!("Evaluation Error: {e}"),
+                site.and_then(|at| spans.get(at).copied()),
+            ),
+            e => (format!("Evaluation Error: {e}"), None),
+        };
+        self.fail(message, span.map(|span| (span, expr)));
+    }
+
+    // Loads the standard library into the environment.
+    fn load_stdlib(&mut self) {
+        if let Err(e) = self.load(Path::new("stdlib.conf")) {
+            self.fail(format!("Failed to open stdlib.conf: {e}"), None);
+        }
+    }
+
+    // Parses the term and binds it to the name in the environment.
+    fn define(&mut self, name: &str, term: &str) {
+        if let Some((t, _)) = self.parse_populated(term) {
+            self.put_env(name.into(), t);
+        }
+    }
+
+    // Loads a module into the environment, first loading the modules it imports, and running its
+    // commands and definitions in order. Gives the module's main expression along with where it
+    // comes from, which is the definition of main if there is one, or else the last expression.
+    fn load(&mut self, path: &Path) -> std::io::Result<Option<(String, Source)>> {
+        let text: Rc<str> = fs::read_to_string(path)?.into();
+        let canonical = fs::canonicalize(path)?;
+        if self.loading.contains(&canonical) {
+            return Err(std::io::Error::other(format!(
+                "import cycle through {}",
+                path.display()
+            )));
+        }
+
+        self.loading.push(canonical);
+        let path_name: Rc<str> = path.display().to_string().into();
+        let mut main = None;
+        for item in items(&text) {
+            let source = |offset| Source {
+                path: path_name.clone(),
+                text: text.clone(),
+                offset,
+            };
+            self.source = Some(source(item.offset));
+            if let Some(import) = item.import() {
+                let imported = path.parent().unwrap_or(Path::new("")).join(import);
+                if let Err(e) = self.load(&imported) {
+                    let span = Span::new(0, item.text.chars().count());
+                    self.source = Some(source(item.offset));
+                    self.fail(format!("Failed to import {import}: {e}"), Some((span, "")));
+                }
+            } else if item.text.starts_with(':') || item.text.starts_with("gate ") {
+                self.source = None;
+                self.read_line(item.text);
+            } else if let Some((name, term)) = definition(item.text) {
+                let term = term.trim();
+                self.source = Some(source(item.offset_of(term)));
+                self.define(&name, term);
+                if name == "main" {
+                    main = Some((term.to_string(), source(item.offset_of(term))));
+                }
+            } else {
+                main = Some((item.text.to_string(), source(item.offset)));
+            }
+        }
+        self.source = None;
+        self.loading.pop();
+        Ok(main)
+    }
+
+    // Evaluates the expression while printing each reduction step taken.
+    fn trace(&mut self, expr: &str) {
+        let expr = expr.trim();
+        if let Some((t, spans)) = self.parse_populated(expr) {
+            self.evaluator.set_tracing(true);
+            let res = self.evaluator.eval(t);
+            let steps = self.evaluator.take_trace();
+            self.evaluator.set_tracing(false);
+            for (i, step) in steps.iter().enumerate() {
+                println!("{:>4} {step}", i + 1);
+            }
+            match res {
+                Ok(v) => println!("{v}"),
+                Err(e) => self.report(e, expr, &spans),
+            }
+        }
+    }
+
+    // Prints the inferred type of the expression.
+    fn type_of(&mut self, expr: &str) {
+        let expr = expr.trim();
+        if let Some((t, spans)) = self.parse_populated(expr) {
+            match self.evaluator.type_of(&t) {
+                Ok(a) => println!("{a}"),
+                Err(e) => self.report(EvalError::TypeError(e), expr, &spans),
+            }
+        }
+    }
+
+    // Sets or shows the limits on each evaluation, given as "steps N" or "time SECONDS", where
+    // "none" lifts the limit.
+    fn limit(&mut self, arg: &str) {
+        let (steps, time) = self.evaluator.limits();
+        match arg.split_once(' ') {
+            None if arg.is_empty() => {
+                match steps {
+                    Some(n) => println!("steps: {n}"),
+                    None => println!("steps: none"),
+                }
+                match time {
+                    Some(t) => println!("time: {}s", t.as_secs_f64()),
+                    None => println!("time: none"),
+                }
+            }
+            Some(("steps", "none")) => self.evaluator.set_step_limit(None),
+            Some(("steps", n)) => match n.parse() {
+                Ok(n) => self.evaluator.set_step_limit(Some(n)),
+                Err(_) => println!("Step limit must be a nonnegative integer: {n}"),
+            },
+            Some(("time", "none")) => self.evaluator.set_time_limit(None),
+            Some(("time", t)) => match t
+                .parse()
+                .ok()
+                .and_then(|t| Duration::try_from_secs_f64(t).ok())
+            {
+                Some(t) => self.evaluator.set_time_limit(Some(t)),
+                None => println!("Time limit must be a nonnegative number of seconds: {t}"),
+            },
+            _ => println!("Unknown limit {arg}: expected steps N or time SECONDS"),
+        }
+    }
+
+    // Evaluates the expression the given number of times, printing how often each normal form
+    // occurred.
+    pub fn shots(&mut self, n: usize, expr: &str) {
+        let expr = expr.trim();
+        if let Some((t, spans)) = self.parse_populated(expr) {
+            let mut hist = Histogram::new();
+            for _ in 0..n {
+                match self.evaluator.eval(t.clone()) {
+                    Ok(v) => hist.record(&v),
+                    Err(e) => {
+                        self.report(e, expr, &spans);
+                        return;
+                    }
+                }
+            }
+            println!("{hist}");
+        }
+    }
+
+    // Evaluates the expression and writes its state to a file, in the format given by the file's
+    // extension.
+    fn export(&mut self, path: &Path, expr: &str) {
+        let Some(format) = Format::from_path(path) else {
+            let message = format!(
+                "Unknown export format for {}: expected a .json or .npy file",
+                path.display()
+            );
+            return self.fail(message, None);
+        };
+        let expr = expr.trim();
+        if let Some((t, spans)) = self.parse_populated(expr) {
+            let v = match self.evaluator.eval(t) {
+                Ok(v) => v,
+                Err(e) => return self.report(e, expr, &spans),
+            };
+            match export(&v, format) {
+                Ok(bytes) => {
+                    if let Err(e) = fs::write(path, bytes) {
+                        self.fail(format!("Failed to export to {}: {e}", path.display()), None);
+                    }
+                }
+                Err(e) => self.fail(format!("Export Error: {e}"), None),
+            }
+        }
+    }
+
+    // Evaluates the expression once and prints its state as JSON.
+    fn evaluate_json(&mut self, expr: &str) {
+        if let Some((t, spans)) = self.parse_populated(expr) {
+            match self.evaluator.eval(t) {
+                Ok(v) => print!("{}", json(&v)),
+                Err(e) => self.report(e, expr, &spans),
+            }
+        }
+    }
+
+    // Defines a gate from its unitary matrix, making it available under the given name.
+    fn define_gate(&mut self, name: &str, matrix: &str) {
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return self.fail(format!("Invalid gate name: {name}"), None);
+        }
+        if GATES.contains(&name) || name == "M" {
+            return self.fail(format!("Cannot redefine built-in gate {name}"), None);
+        }
+
+        match parse_unitary(matrix) {
+            Ok(u) => {
+                self.evaluator.define_gate(name, u);
+                self.put_env(name.into(), gate(name));
+            }
+            Err(e) => self.fail(format!("Gate Error: {e}"), None),
+        }
+    }
+
+    // Runs a colon-prefixed command with the rest of the line as its argument.
+    fn command(&mut self, name: &str, arg: &str) {
+        match name {
+            "trace" => self.trace(arg),
+            "seed" => match arg.parse() {
+                Ok(seed) => self.evaluator.reseed(seed),
+                Err(_) => println!("Seed must be a nonnegative integer: {arg}"),
+            },
+            "measure" => match arg {
+                "sample" => self.evaluator.set_measurement(Measurement::Sample),
+                "exact" => self.evaluator.set_measurement(Measurement::Exact),
+                _ => println!("Unknown measurement mode {arg}: expected sample or exact"),
+            },
+            "backend" => match arg {
+                "sparse" => self.evaluator.set_backend(Backend::Sparse),
+                "dense" => self.evaluator.set_backend(Backend::Dense),
+                _ => println!("Unknown backend {arg}: expected sparse or dense"),
+            },
+            "engine" => match arg {
+                "substitution" => self.evaluator.set_engine(Engine::Substitution),
+                "machine" => self.evaluator.set_engine(Engine::Machine),
+                _ => println!("Unknown engine {arg}: expected substitution or machine"),
+            },
+            "strategy" => match arg {
+                "value" => self.evaluator.set_strategy(Strategy::ByValue),
+                "name" => self.evaluator.set_strategy(Strategy::ByName),
+                "normal" => self.evaluator.set_strategy(Strategy::NormalOrder),
+                _ => println!("Unknown strategy {arg}: expected value, name or normal"),
+            },
+            "type" => self.type_of(arg),
+            "typecheck" => match arg {
+                "on" => self.evaluator.set_type_checking(true),
+                "off" => self.evaluator.set_type_checking(false),
+                _ => println!("Unknown type checking mode {arg}: expected on or off"),
+            },
+            "limit" => self.limit(arg),
+            "load" => match self.load(Path::new(arg)) {
+                Ok(_) => (),
+                Err(e) => println!("Failed to load {arg}: {e}"),
+            },
+            "shots" => {
+                let (n, expr) = arg.split_once(' ').unwrap_or((arg, ""));
+                match n.parse() {
+                    Ok(n) => self.shots(n, expr),
+                    Err(_) => println!("Number of shots must be a nonnegative integer: {n}"),
+                }
+            }
+            "export" => {
+                let (path, expr) = arg.split_once(' ').unwrap_or((arg, ""));
+                self.export(Path::new(path), expr);
+            }
+            _ => println!("Unknown command :{name}"),
+        }
+    }
+
     // Processes a line of input and performs the corresponding effects.
     pub fn read_line(&mut self, line: &str) {
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            return;
+        }
+        if let Some(cmd) = line.strip_prefix(':') {
+            let (name, arg) = cmd.split_once(' ').unwrap_or((cmd, ""));
+            self.command(name, arg.trim());
+            return;
+        }
+
         // Check for keyword commands
         match line {
             "quit" => {
@@ -66,75 +444,304 @@ impl Repl {
                 println!("Reserved identifiers are:");
                 println!("|0>, |1>: Qubit basis states");
                 println!("H: Hadamard Gate");
-                println!("C: CNOT Gate");
-                println!("T: T Gate");
+                println!("X, Y, Z: Pauli Gates");
+                println!("S, Sdg: Phase Gate and its inverse");
+                println!("T, Tdg: T Gate and its inverse");
+                println!("C: CNOT Gate, on a pair of qubits");
+                println!("CZ: Controlled-Z Gate, on a pair of qubits");
+                println!("SWAP: Swap Gate, on a pair of qubits");
+                println!("CCX: Toffoli Gate, on a triple of qubits");
+                println!("Rx(θ), Ry(θ), Rz(θ): Rotations by the angle θ");
+                println!("Phase(θ): Phase shift by the angle θ");
                 println!("M: Measurement");
                 println!("You can create lambdas with syntax like \\x.x");
                 println!("You can assign variables like \"NAME = VALUE\"");
+                println!("You can bind variables locally like \"let x = VALUE in BODY\"");
+                println!("You can take tuples apart like \"let (x, y) = PAIR in BODY\"");
+                println!("You can write tuples like (|0>, |1>) and lists like [|0>, |1>]");
+                println!("You can define gates like \"gate NAME = [[0, 1], [1, 0]]\"");
+                println!("You can infer the type of an expression with \":type EXPR\"");
+                println!("You can evaluate untyped terms like omega with \":typecheck off\"");
+                println!("You can print each reduction step with \":trace EXPR\"");
+                println!("You can make measurements reproducible with \":seed N\"");
+                println!("You can get exact outcome distributions with \":measure exact\"");
+                println!("You can simulate large registers with \":backend dense\"");
+                println!("You can evaluate on an environment machine with \":engine machine\"");
+                println!("You can reduce under lambdas with \":strategy normal\"");
+                println!("You can limit the steps or seconds of evaluations with \":limit\"");
+                println!("You can tally the outcomes of N evaluations with \":shots N EXPR\"");
+                println!("You can load the definitions in a module with \":load FILEPATH\"");
+                println!("You can save a state as JSON or NumPy with \":export FILEPATH EXPR\"");
+                return;
             }
             _ => (),
         }
 
-        if let Some((name, term)) = line.split_once('=') {
-            match parse(&mut term.trim().chars()) {
-                Ok(t) => {
-                    let t = populate_term(t, &self.env);
-                    self.put_env(name.trim().into(), t);
-                }
-                Err(e) => println!("Parser Error: {:?}", e),
-            }
+        if let Some((name, matrix)) = line
+            .strip_prefix("gate ")
+            .and_then(|def| def.split_once('='))
+        {
+            self.define_gate(name.trim(), matrix);
+            return;
+        }
+
+        if let Some((name, term)) = definition(line) {
+            self.define(&name, term.trim());
         } else {
-            match parse(&mut line.chars()) {
-                Ok(t) => {
-                    let t = populate_term(t, &self.env);
-                    match eval(t) {
-                        Ok(v) => println!("{v}"),
-                        Err(e) => println!("Evaluation Error: {:?}", e),
-                    }
-                }
-                Err(e) => println!("Parser Error: {:?}", e),
+            self.evaluate(line);
+        }
+    }
+
+    // Evaluates the expression once and prints its normal form.
+    fn evaluate(&mut self, expr: &str) {
+        if let Some((t, spans)) = self.parse_populated(expr) {
+            match self.evaluator.eval(t) {
+                Ok(v) => println!("{v}"),
+                Err(e) => self.report(e, expr, &spans),
             }
         }
     }
 }
 
-// Replaces symbols in this term with their corresponding term in the environment.
+// Splits a definition "NAME = TERM" into its name and term, if the line is one rather than an
+// expression such as a let.
+fn definition(line: &str) -> Option<(String, &str)> {
+    let (name, term) = line.split_once('=')?;
+    match parse(&mut name.chars()) {
+        Ok(Term::Var(ref name)) => Some((name.clone(), term)),
+        _ => None,
+    }
+}
+
+// Counts the subterms of a term, including itself.
+fn size(t: &Term) -> usize {
+    let mut stack = vec![t];
+    let mut n = 0;
+    while let Some(t) = stack.pop() {
+        n += 1;
+        match t {
+            Term::Const(_) | Term::Var(_) => (),
+            Term::Abs(_, body) | Term::NonlinearAbs(_, body) | Term::Nonlinear(body) => {
+                stack.push(body)
+            }
+            Term::App(t1, t2) => stack.extend([&**t2, &**t1]),
+        }
+    }
+    n
+}
+
+// Gets the spans of the subterms of a term in pre-order once the environment is substituted into
+// it, given their spans beforehand, which every subterm of a substituted symbol shares.
+fn populate_spans(t: &Term, spans: &[Span], env: &HashMap<String, Term>) -> Vec<Span> {
+    enum Task<'a> {
+        Visit(&'a Term),
+        Unbind(&'a str),
+    }
+
+    let mut stack = vec![Task::Visit(t)];
+    let mut bound = Bound::default();
+    let mut out = Vec::with_capacity(spans.len());
+    let mut spans = spans.iter();
+    while let Some(task) = stack.pop() {
+        let t = match task {
+            Task::Visit(t) => t,
+            Task::Unbind(x) => {
+                bound.unbind(x);
+                continue;
+            }
+        };
+        let span = *spans.next().expect("missing span of subterm");
+        match t {
+            Term::Var(x) if let Some(t2) = bound.lookup(x, env) => {
+                out.extend(std::iter::repeat_n(span, size(t2)))
+            }
+            Term::Const(_) | Term::Var(_) => out.push(span),
+            Term::Abs(x, body) | Term::NonlinearAbs(x, body) => {
+                out.push(span);
+                bound.bind(x);
+                stack.extend([Task::Unbind(x), Task::Visit(body)]);
+            }
+            Term::Nonlinear(body) => {
+                out.push(span);
+                stack.push(Task::Visit(body));
+            }
+            Term::App(t1, t2) => {
+                out.push(span);
+                stack.extend([Task::Visit(t2), Task::Visit(t1)]);
+            }
+        }
+    }
+    out
+}
+
+// Counts the binders of each variable in scope while walking a term, so that only the symbols
+// which are free are taken from the environment.
+#[derive(Default)]
+struct Bound(HashMap<String, usize>);
+
+impl Bound {
+    fn bind(&mut self, x: &str) {
+        *self.0.entry(x.to_string()).or_default() += 1;
+    }
+
+    fn unbind(&mut self, x: &str) {
+        if let Some(n) = self.0.get_mut(x) {
+            *n -= 1;
+        }
+    }
+
+    // Gets the term for a symbol from the environment, unless the symbol is bound.
+    fn lookup<'a>(&self, x: &str, env: &'a HashMap<String, Term>) -> Option<&'a Term> {
+        match self.0.get(x) {
+            Some(n) if *n > 0 => None,
+            _ => env.get(x),
+        }
+    }
+}
+
+// Replaces free symbols in this term with their corresponding term in the environment. The term is
+// rebuilt from an explicit stack, so that deeply nested terms can't overflow the call stack.
 pub fn populate_term(t: Term, env: &HashMap<String, Term>) -> Term {
-    match t {
-        Term::Const(_) => t,
-        Term::Var(ref x) => match env.get(x) {
-            Some(t2) => t2.clone(),
-            None => t,
-        },
-        Term::Abs(x, body) => abs(&x, populate_term(*body, env)),
-        Term::NonlinearAbs(x, body) => nonlinear_abs(&x, populate_term(*body, env)),
-        Term::Nonlinear(t2) => nonlinear(populate_term(*t2, env)),
-        Term::App(t1, t2) => app(populate_term(*t1, env), populate_term(*t2, env)),
+    enum Task {
+        Visit(Term),
+        Abs(String),
+        NonlinearAbs(String),
+        App,
+        Nonlinear,
     }
+
+    let mut stack = vec![Task::Visit(t)];
+    let mut bound = Bound::default();
+    let mut out: Vec<Term> = Vec::new();
+    while let Some(task) = stack.pop() {
+        match task {
+            Task::Visit(t) => match t.into_node() {
+                Node::Const(c) => out.push(Term::Const(c)),
+                Node::Var(x) => match bound.lookup(&x, env) {
+                    Some(t2) => out.push(t2.clone()),
+                    None => out.push(Term::Var(x)),
+                },
+                Node::Abs(x, body) => {
+                    bound.bind(&x);
+                    stack.extend([Task::Abs(x), Task::Visit(body)])
+                }
+                Node::NonlinearAbs(x, body) => {
+                    bound.bind(&x);
+                    stack.extend([Task::NonlinearAbs(x), Task::Visit(body)])
+                }
+                Node::Nonlinear(t2) => stack.extend([Task::Nonlinear, Task::Visit(t2)]),
+                Node::App(t1, t2) => stack.extend([Task::App, Task::Visit(t2), Task::Visit(t1)]),
+            },
+            task => {
+                let mut pop = || out.pop().expect("missing populated subterm");
+                let t = match task {
+                    Task::Abs(x) => {
+                        bound.unbind(&x);
+                        abs(&x, pop())
+                    }
+                    Task::NonlinearAbs(x) => {
+                        bound.unbind(&x);
+                        nonlinear_abs(&x, pop())
+                    }
+                    Task::Nonlinear => nonlinear(pop()),
+                    Task::App => {
+                        let t2 = pop();
+                        app(pop(), t2)
+                    }
+                    Task::Visit(_) => unreachable!(),
+                };
+                out.push(t);
+            }
+        }
+    }
+    out.pop().expect("missing populated term")
+}
+
+// Marks each gate and rotation in a populated term with its site in a pre-order traversal of the
+// term, which is that of the application of the gate to its argument if the term has one, and
+// otherwise that of the gate itself.
+fn mark_sites(t: &mut Term) {
+    let mut stack = vec![(t, None)];
+    let mut at = 0;
+    while let Some((t, app)) = stack.pop() {
+        match t {
+            Term::Const(Const::Gate(_, site) | Const::Rotation(_, _, site)) => {
+                *site = Some(app.unwrap_or(at))
+            }
+            Term::Const(_) | Term::Var(_) => (),
+            Term::Abs(_, body) | Term::NonlinearAbs(_, body) | Term::Nonlinear(body) => {
+                stack.push((body, None))
+            }
+            Term::App(t1, t2) => stack.extend([(&mut **t2, None), (&mut **t1, Some(at))]),
+        }
+        at += 1;
+    }
+}
+
+// How the main expression of a module is run from the command line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RunMode {
+    // Evaluate it once and print its normal form.
+    Evaluate,
+    // Evaluate it the given number of times and print a histogram of the outcomes.
+    Shots(usize),
+    // Evaluate it once and print its state as JSON.
+    Json,
 }
 
-// Loads the contents of the specified file into the environment line-by-line.
-fn load_file(path: &Path, repl: &mut Repl) -> std::io::Result<()> {
-    let mut file = File::open(path)?;
-    let mut contents = String::new();
-    file.read_to_string(&mut contents)?;
+// Runs the module in the given file after loading the standard library, evaluating its main
+// expression as the mode says unless loading the module reported errors. Gives the errors
+// reported if there were any, which are also written as a JSON object in the JSON mode.
+pub fn run(
+    path: &Path,
+    mode: RunMode,
+    evaluator: Evaluator,
+) -> std::result::Result<(), Vec<String>> {
+    let mut repl = Repl::with_evaluator(evaluator);
+    repl.load_stdlib();
 
-    for line in contents.lines() {
-        repl.read_line(line);
+    match repl.load(path) {
+        Ok(Some((expr, source))) if repl.errors.is_empty() => {
+            repl.source = Some(source);
+            match mode {
+                RunMode::Evaluate => repl.evaluate(&expr),
+                RunMode::Shots(n) => repl.shots(n, &expr),
+                RunMode::Json => repl.evaluate_json(&expr),
+            }
+            repl.source = None;
+        }
+        Ok(Some(_)) => (),
+        Ok(None) => repl.fail(format!("No expression to run in {}", path.display()), None),
+        Err(e) => repl.fail(format!("Failed to load {}: {e}", path.display()), None),
     }
 
-    Ok(())
+    if repl.errors.is_empty() {
+        return Ok(());
+    }
+    if mode == RunMode::Json {
+        print!("{}", json_errors(&repl.errors));
+    }
+    Err(repl.errors)
 }
 
-// Runs a new REPL until an error is encountered.
-pub fn repl() -> Result<()> {
-    let mut rl = DefaultEditor::new()?;
-    let mut repl = Repl::new();
+// Raised by Ctrl-C while the REPL is evaluating, to stop the evaluation instead of the REPL.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
 
-    match load_file(Path::new("stdlib.conf"), &mut repl) {
-        Ok(_) => (),
-        Err(e) => println!("Failed to open stdlib.conf: {e}"),
+// Makes Ctrl-C raise the interrupt flag rather than terminating the process.
+fn catch_interrupts() {
+    if let Err(e) = ctrlc::set_handler(|| INTERRUPTED.store(true, Ordering::Relaxed)) {
+        println!("Failed to catch Ctrl-C, so it will exit rather than stop an evaluation: {e}");
     }
+}
+
+// Runs a new REPL with the given evaluator until an error is encountered.
+pub fn repl(mut evaluator: Evaluator) -> Result<()> {
+    catch_interrupts();
+    evaluator.set_interrupt(&INTERRUPTED);
+    let mut rl = DefaultEditor::new()?;
+    let mut repl = Repl::with_evaluator(evaluator);
+
+    repl.load_stdlib();
 
     loop {
         match rl.readline("qlam> ") {
@@ -144,6 +751,8 @@ pub fn repl() -> Result<()> {
                 }
                 rl.add_history_entry(line.as_str())?;
                 repl.read_line(&line);
+                // Errors are only kept to tell whether running a module failed
+                repl.errors.clear();
             }
             Err(ReadlineError::Eof) | Err(ReadlineError::Interrupted) => {
                 break;
@@ -153,3 +762,103 @@ pub fn repl() -> Result<()> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Repl, populate_term};
+    use crate::{
+        parser::parse,
+        span::Span,
+        term::{Engine, EvalError, Evaluator, Measurement, Value},
+    };
+
+    // Programs built from the standard library, on top of each of its definitions on its own.
+    const PROGRAMS: &[&str] = &[
+        "swap (|0>, |1>)",
+        "pair (H |0>) |1>",
+        "trip |0> (H |1>) |0>",
+        "M (pair (H |0>) (H |1>))",
+        "deutsch (\\p. p)",
+        "deutsch C",
+        "M (deutsch C)",
+        "epr",
+        "M epr",
+        "alice (H |1>, |0>)",
+        "teleport |1>",
+        "teleport (H |0>)",
+        "M (teleport (H |1>))",
+        "id !(H |0>)",
+        "succ !(succ !(zero))",
+        "succ !(succ !(zero)) !(H) !(|0>)",
+        "cons (H |0>) (cons |1> emp)",
+        "fix !(#f. \\x. x) |0>",
+    ];
+
+    // Evaluates a term with the given engine and measurement mode, from a fixed seed.
+    fn eval(
+        repl: &Repl,
+        source: &str,
+        engine: Engine,
+        measurement: Measurement,
+    ) -> Result<Value, EvalError> {
+        let t = populate_term(parse(&mut source.chars()).unwrap(), &repl.env);
+        let mut ev = Evaluator::seeded(7);
+        ev.set_engine(engine);
+        ev.set_measurement(measurement);
+        ev.set_type_checking(false);
+        ev.set_step_limit(Some(10_000));
+        ev.eval(t)
+    }
+
+    #[test]
+    fn engines_agree_on_the_standard_library() {
+        let mut repl = Repl::new();
+        repl.load_stdlib();
+        assert!(repl.errors.is_empty(), "{:?}", repl.errors);
+        let mut names: Vec<&str> = repl.env.keys().map(String::as_str).collect();
+        names.sort();
+        assert!(
+            names.contains(&"teleport"),
+            "the standard library wasn't loaded"
+        );
+
+        for source in names.into_iter().chain(PROGRAMS.iter().copied()) {
+            for measurement in [Measurement::Sample, Measurement::Exact] {
+                let by_substitution = eval(&repl, source, Engine::Substitution, measurement);
+                let by_machine = eval(&repl, source, Engine::Machine, measurement);
+                match (&by_substitution, &by_machine) {
+                    (Ok(v1), Ok(v2)) => assert!(v1.alpha_eq(v2), "{source}: {v1} but {v2}"),
+                    (Err(EvalError::OutOfFuel(_)), Err(EvalError::OutOfFuel(_))) => (),
+                    // The stuck terms in errors may name their bound variables differently.
+                    (Err(e1), Err(e2)) => assert_eq!(
+                        std::mem::discriminant(e1),
+                        std::mem::discriminant(e2),
+                        "{source}: {e1} but {e2}"
+                    ),
+                    _ => panic!("{source}: {by_substitution:?} but {by_machine:?}"),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn gate_errors_point_at_the_application() {
+        let mut repl = Repl::new();
+        repl.load_stdlib();
+        repl.evaluator.set_type_checking(false);
+        for engine in [Engine::Substitution, Engine::Machine] {
+            repl.evaluator.set_engine(engine);
+            for (source, span) in [
+                ("id (H (|0>, |0>))", Span::new(3, 17)),
+                ("Rx(0.5) (|0>, |0>)", Span::new(0, 18)),
+                ("(\\g. g (|0>, |1>)) X", Span::new(19, 20)),
+            ] {
+                let (t, spans) = repl.parse_populated(source).unwrap();
+                let Err(EvalError::BadGate(_, Some(at))) = repl.evaluator.eval(t) else {
+                    panic!("{source} didn't fail at a gate");
+                };
+                assert_eq!(spans[at], span, "{source}");
+            }
+        }
+    }
+}
@@ -0,0 +1,65 @@
+// A range of characters in some source, from start up to but not including end.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+
+    // Gets the smallest span covering both spans.
+    pub fn to(self, other: Span) -> Span {
+        Span::new(self.start.min(other.start), self.end.max(other.end))
+    }
+
+    // Gets the line and column, both counted from 1, where the span starts in the source.
+    pub fn position(&self, src: &str) -> (usize, usize) {
+        let before = src.chars().take(self.start);
+        before.fold((1, 1), |(line, col), c| match c {
+            '\n' => (line + 1, 1),
+            _ => (line, col + 1),
+        })
+    }
+
+    // Moves the span later in the source by the given number of characters.
+    pub fn shift(self, by: usize) -> Span {
+        Span::new(self.start + by, self.end + by)
+    }
+
+    // Renders the lines of the source the span covers, numbered and with the span underlined by
+    // carets, in the style of rustc. An empty span is shown as a single caret where it starts.
+    pub fn render(&self, src: &str) -> String {
+        let mut lines = Vec::new();
+        let mut start = 0;
+        for (n, line) in src.split('\n').enumerate() {
+            let end = start + line.chars().count();
+            let covered = if self.start == self.end {
+                start <= self.start && self.start <= end
+            } else {
+                self.start <= end && start < self.end
+            };
+            if covered {
+                let from = self.start.max(start) - start;
+                let to = self.end.min(end) - start;
+                lines.push((n + 1, line, from, to.saturating_sub(from).max(1)));
+            }
+            start = end + 1;
+        }
+
+        let width = lines.last().map_or(1, |(n, ..)| n.to_string().len());
+        let mut out = format!("{:width$} |\n", "");
+        for (n, line, from, carets) in lines {
+            out += &format!("{n:>width$} | {line}\n");
+            out += &format!(
+                "{:width$} | {}{}\n",
+                "",
+                " ".repeat(from),
+                "^".repeat(carets)
+            );
+        }
+        out
+    }
+}
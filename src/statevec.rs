@@ -0,0 +1,158 @@
+use num::Complex;
+use rand::Rng;
+
+// How many amplitudes a gate goes through between calls to its check.
+const CHECK_EVERY: usize = 1 << 16;
+
+// A dense quantum register holding the joint state of every qubit allocated during an evaluation.
+// Qubit q is bit q of the basis state index.
+#[derive(Debug, Clone)]
+pub struct StateVector {
+    qubits: usize,
+    amps: Vec<Complex<f64>>,
+}
+
+impl Default for StateVector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StateVector {
+    // Creates the register with no qubits.
+    pub fn new() -> Self {
+        Self {
+            qubits: 0,
+            amps: vec![Complex::new(1.0, 0.0)],
+        }
+    }
+
+    // Gets the number of qubits in the register.
+    pub fn qubits(&self) -> usize {
+        self.qubits
+    }
+
+    // Adds a new qubit in the basis state |b> to the register, returning its index.
+    pub fn alloc(&mut self, b: bool) -> usize {
+        let zeros = vec![Complex::new(0.0, 0.0); self.amps.len()];
+        if b {
+            self.amps.splice(0..0, zeros);
+        } else {
+            self.amps.extend(zeros);
+        }
+        self.qubits += 1;
+        self.qubits - 1
+    }
+
+    // Applies a gate to the given qubits, where the gate is described by the image of each basis
+    // state of those qubits. The bits passed to the image are in the same order as the qubits.
+    // Since a large register takes a while to go through, the check is called every so often
+    // along the way, and an error it gives stops the gate, leaving the register as it was.
+    pub fn apply<F, C, E>(&mut self, qs: &[usize], image: F, mut check: C) -> Result<(), E>
+    where
+        F: Fn(&[bool]) -> Vec<(Vec<bool>, Complex<f64>)>,
+        C: FnMut() -> Result<(), E>,
+    {
+        // Images of each local basis state, with bit j of a local index belonging to qs[j]
+        let images: Vec<Vec<(usize, Complex<f64>)>> = (0..1 << qs.len())
+            .map(|local| {
+                let bits: Vec<bool> = (0..qs.len()).map(|j| (local >> j) & 1 == 1).collect();
+                image(&bits)
+                    .into_iter()
+                    .map(|(out, amp)| (to_local(&out), amp))
+                    .collect()
+            })
+            .collect();
+        let mask = qs.iter().fold(0, |acc, q| acc | (1 << q));
+
+        let mut out = vec![Complex::new(0.0, 0.0); self.amps.len()];
+        for (i, amp) in self.amps.iter().enumerate() {
+            if i % CHECK_EVERY == CHECK_EVERY - 1 {
+                check()?;
+            }
+            if amp.norm_sqr() == 0.0 {
+                continue;
+            }
+            let local = gather(i, qs);
+            for (image, a) in &images[local] {
+                out[(i & !mask) | scatter(*image, qs)] += amp * a;
+            }
+        }
+        self.amps = out;
+        Ok(())
+    }
+
+    // Measures the given qubits, collapsing the register onto the sampled outcome. A basis state
+    // of the whole register is sampled and the outcome read off it, so that no table of
+    // probabilities over the outcomes is ever built.
+    pub fn measure<R: Rng>(&mut self, qs: &[usize], rng: &mut R) -> Vec<bool> {
+        let r: f64 = rng.r#gen();
+        let mut acc = 0.0;
+        let mut sampled = None;
+        for (i, amp) in self.amps.iter().enumerate() {
+            let p = amp.norm_sqr();
+            if p > 0.0 {
+                // Rounding can leave the probabilities a little short of 1, so the last state
+                // with any probability is taken if r is never reached
+                sampled = Some(i);
+                acc += p;
+                if acc >= r {
+                    break;
+                }
+            }
+        }
+        let outcome = gather(sampled.unwrap_or(0), qs);
+
+        let norm: f64 = self
+            .amps
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| gather(*i, qs) == outcome)
+            .map(|(_, amp)| amp.norm_sqr())
+            .sum::<f64>()
+            .sqrt();
+        for (i, amp) in self.amps.iter_mut().enumerate() {
+            if gather(i, qs) == outcome {
+                *amp /= norm;
+            } else {
+                *amp = Complex::new(0.0, 0.0);
+            }
+        }
+        (0..qs.len()).map(|j| (outcome >> j) & 1 == 1).collect()
+    }
+
+    // Iterates over the basis states with non-negligible amplitude.
+    pub fn branches(&self) -> impl Iterator<Item = (usize, Complex<f64>)> + '_ {
+        self.amps
+            .iter()
+            .enumerate()
+            .filter(|(_, amp)| amp.norm_sqr() > 1e-9)
+            .map(|(i, amp)| (i, *amp))
+    }
+}
+
+// Gets the value of qubit q in the basis state with the given index.
+pub fn bit(index: usize, q: usize) -> bool {
+    (index >> q) & 1 == 1
+}
+
+// Packs the bits of the given qubits in a basis state index into a local index.
+fn gather(index: usize, qs: &[usize]) -> usize {
+    qs.iter()
+        .enumerate()
+        .fold(0, |acc, (j, q)| acc | (((index >> q) & 1) << j))
+}
+
+// Unpacks a local index into the bits of the given qubits.
+fn scatter(local: usize, qs: &[usize]) -> usize {
+    qs.iter()
+        .enumerate()
+        .fold(0, |acc, (j, q)| acc | (((local >> j) & 1) << q))
+}
+
+// Packs a list of bits into a local index.
+fn to_local(bits: &[bool]) -> usize {
+    bits.iter()
+        .enumerate()
+        .fold(0, |acc, (j, b)| acc | ((*b as usize) << j))
+}
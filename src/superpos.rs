@@ -1,6 +1,11 @@
-use crate::term::{EvalError, Term, Value};
+use crate::{
+    debruijn::Interner,
+    pretty,
+    term::{EvalError, Term, Value},
+};
 use num_complex::Complex;
 use rand::Rng;
+use std::collections::{HashMap, hash_map::Entry};
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Superpos(pub Vec<(Term, Complex<f64>)>);
@@ -11,14 +16,21 @@ impl Superpos {
         Self(vec![(init, Complex::new(1.0, 0.0))])
     }
 
-    // Merges identical terms in the branches of the superposition.
+    // Merges alpha-equivalent terms in the branches of the superposition, keeping the order and
+    // bound variable names with which each term first appears. The branches are interned
+    // together, so that the subterms they share are only hashed once.
     pub fn merge(&mut self) {
+        let mut interner = Interner::default();
+        let ids: Vec<usize> = self.0.iter().map(|(t, _)| interner.intern(t)).collect();
         let mut merged: Vec<(Term, Complex<f64>)> = Vec::new();
-        for (t, amp) in self.0.iter() {
-            if let Some((_, cur)) = merged.iter_mut().find(|(u, _)| *u == *t) {
-                *cur += amp;
-            } else {
-                merged.push((t.clone(), *amp));
+        let mut index: HashMap<usize, usize> = HashMap::new();
+        for ((t, amp), id) in std::mem::take(&mut self.0).into_iter().zip(ids) {
+            match index.entry(id) {
+                Entry::Occupied(e) => merged[*e.get()].1 += amp,
+                Entry::Vacant(e) => {
+                    e.insert(merged.len());
+                    merged.push((t, amp));
+                }
             }
         }
         merged.retain(|(_, amp)| amp.norm_sqr() > 1e-9);
@@ -27,53 +39,47 @@ impl Superpos {
 
     // Maps the function over the branches of the superposition, flattening any newly-generated
     // superpositions into the toplevel one.
-    pub fn map_terms<F>(&self, f: F) -> Result<Self, EvalError>
+    pub fn map_terms<F>(&self, mut f: F) -> Result<Value, EvalError>
     where
-        F: Fn(Term) -> Result<Value, EvalError>,
+        F: FnMut(Term) -> Result<Value, EvalError>,
     {
         let mut out = Vec::new();
         for (t, amp) in &self.0 {
-            match f(t.clone())? {
-                Value::Term(t2) => out.push((t2, *amp)),
-                // When we flatten here we need to multiply to get the joint probability
-                Value::Superpos(s) => {
-                    for (u, amp2) in s.0 {
-                        out.push((u.clone(), amp * amp2))
-                    }
-                }
-            }
+            out.push((f(t.clone())?, *amp));
         }
-        Ok(Self(out))
+        Ok(combine(out))
     }
 
     // Maps the binary function over both superpositions, taking their branchwise product and
     // flattening as in map_terms.
-    pub fn zip_terms<F>(&self, other: &Superpos, f: F) -> Result<Self, EvalError>
+    pub fn zip_terms<F>(&self, other: &Superpos, mut f: F) -> Result<Value, EvalError>
     where
-        F: Fn(Term, Term) -> Result<Value, EvalError>,
+        F: FnMut(Term, Term) -> Result<Value, EvalError>,
     {
         let mut out = Vec::new();
         for (t1, amp1) in &self.0 {
             for (t2, amp2) in &other.0 {
-                match f(t1.clone(), t2.clone())? {
-                    Value::Term(t3) => out.push((t3, amp1 * amp2)),
-                    Value::Superpos(s) => {
-                        for (u, amp3) in &s.0 {
-                            out.push((u.clone(), amp1 * amp2 * amp3))
-                        }
-                    }
-                }
+                out.push((f(t1.clone(), t2.clone())?, amp1 * amp2));
             }
         }
+        Ok(combine(out))
+    }
 
-        Ok(Self(out))
+    // Measures the superposition exactly, giving each distinct branch with its probability.
+    pub fn distribution(mut self) -> Mixture {
+        self.merge();
+        Mixture(
+            self.0
+                .into_iter()
+                .map(|(t, amp)| (Value::Term(t), amp.norm_sqr()))
+                .collect(),
+        )
     }
 
-    // Samples a term from the quantum superposition, consuming this state.
-    // The state vector must be nonempty to avoid panics.
-    pub fn measure(mut self) -> Term {
+    // Samples a term from the quantum superposition using the given source of randomness,
+    // consuming this state. The state vector must be nonempty to avoid panics.
+    pub fn measure<R: Rng>(mut self, rng: &mut R) -> Term {
         self.merge();
-        let mut rng = rand::thread_rng();
         let probs: Vec<f64> = self.0.iter().map(|(_, amp)| amp.norm_sqr()).collect();
 
         let r: f64 = rng.r#gen();
@@ -93,12 +99,125 @@ impl Superpos {
     }
 }
 
+// Flattens the values of the branches of a superposition, each scaled by the amplitude of its
+// branch, into one value. A branch measured exactly gives a mixture, each of whose states would
+// have been the branch had its measurement been sampled, so then the result is the mixture of the
+// superpositions made from every combination of the branches' outcomes, with equal ones merged.
+fn combine(values: Vec<(Value, Complex<f64>)>) -> Value {
+    let mut outcomes = vec![(Superpos(Vec::new()), 1.0)];
+    let mut mixed = false;
+    for (v, amp) in values {
+        match v {
+            Value::Mixed(m) => {
+                mixed = true;
+                let mut next: Vec<(Superpos, f64)> = Vec::new();
+                for (state, p) in &outcomes {
+                    for (u, q) in &m.0 {
+                        let mut s = state.clone();
+                        s.0.extend(scaled(u.clone(), amp));
+                        s.merge();
+                        match next.iter_mut().find(|(s2, _)| same_state(&s, s2)) {
+                            Some((_, p2)) => *p2 += p * q,
+                            None => next.push((s, p * q)),
+                        }
+                    }
+                }
+                outcomes = next;
+            }
+            v => {
+                let branches = scaled(v, amp);
+                if let Some(((last, _), rest)) = outcomes.split_last_mut() {
+                    for (s, _) in rest {
+                        s.0.extend(branches.iter().cloned());
+                    }
+                    last.0.extend(branches);
+                }
+            }
+        }
+    }
+
+    if !mixed {
+        let (s, _) = outcomes.pop().expect("there is always an outcome");
+        return Value::Superpos(s);
+    }
+    let mut m = Mixture(
+        outcomes
+            .into_iter()
+            .map(|(s, p)| (Value::Superpos(s), p))
+            .collect(),
+    );
+    m.0.retain(|(_, p)| *p > 1e-9);
+    Value::Mixed(m)
+}
+
+// Gets the branches of a pure state with their amplitudes multiplied by the given one.
+fn scaled(v: Value, amp: Complex<f64>) -> Vec<(Term, Complex<f64>)> {
+    match v {
+        Value::Term(t) => vec![(t, amp)],
+        Value::Superpos(s) => s.0.into_iter().map(|(t, a)| (t, amp * a)).collect(),
+        Value::Mixed(_) => unreachable!("no state in a mixture is itself a mixture"),
+    }
+}
+
+// Determines if two merged superpositions have the same branches with the same amplitudes, in any
+// order.
+fn same_state(s1: &Superpos, s2: &Superpos) -> bool {
+    s1.0.len() == s2.0.len()
+        && s1.0.iter().all(|(t1, a1)| {
+            s2.0.iter()
+                .any(|(t2, a2)| (a1 - a2).norm() < 1e-9 && t1.alpha_eq(t2))
+        })
+}
+
+// A classical probability distribution over pure states, produced by measuring exactly.
+// No component is itself a mixture.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mixture(pub Vec<(Value, f64)>);
+
+impl Mixture {
+    // Merges alpha-equivalent states in the components of the mixture.
+    pub fn merge(&mut self) {
+        let mut merged: Vec<(Value, f64)> = Vec::new();
+        for (v, p) in self.0.iter() {
+            if let Some((_, cur)) = merged.iter_mut().find(|(u, _)| u.alpha_eq(v)) {
+                *cur += p;
+            } else {
+                merged.push((v.clone(), *p));
+            }
+        }
+        merged.retain(|(_, p)| *p > 1e-9);
+        self.0 = merged;
+    }
+
+    // Maps the function over the components of the mixture, flattening any newly-generated
+    // mixtures into the toplevel one.
+    pub fn map_values<F>(&self, mut f: F) -> Result<Self, EvalError>
+    where
+        F: FnMut(Value) -> Result<Value, EvalError>,
+    {
+        let mut out = Vec::new();
+        for (v, p) in &self.0 {
+            match f(v.clone())? {
+                Value::Mixed(m) => {
+                    for (u, p2) in m.0 {
+                        out.push((u, p * p2))
+                    }
+                }
+                v2 => out.push((v2, *p)),
+            }
+        }
+        Ok(Self(out))
+    }
+}
+
+impl std::fmt::Display for Mixture {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", pretty::mixture(self, pretty::WIDTH))
+    }
+}
+
 impl std::fmt::Display for Superpos {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        writeln!(f, "[")?;
-        for (t, amp) in &self.0 {
-            writeln!(f, "({t}): {amp},")?;
-        }
-        writeln!(f, "]")
+        write!(f, "{}", pretty::superpos(self, pretty::WIDTH))
     }
 }
@@ -1,33 +1,77 @@
 use num::Complex;
+use rand::{SeedableRng, rngs::StdRng};
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
 
 use crate::{
-    helpers::{abs, app, ket, nonlinear, nonlinear_abs, pair, superpos, var},
-    superpos::Superpos,
+    helpers::{abs, app, fresh, ket, nonlinear, nonlinear_abs, superpos, tuple, var},
+    parser::identifier,
+    pretty,
+    statevec::{StateVector, bit},
+    superpos::{Mixture, Superpos},
+    trace::{Step, StepKind},
+    types::{Type, TypeError, infer},
+    unitary::Unitary,
 };
 use std::{
     collections::{HashMap, HashSet},
     f64::consts::PI,
     fmt::Display,
-    iter::empty,
+    hash::{Hash, Hasher},
 };
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Value {
     Term(Term),
     Superpos(Superpos),
+    Mixed(Mixture),
 }
 
-impl Display for Value {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl Value {
+    // Merges alpha-equivalent branches of a superposition, or states of a mixture.
+    pub fn merge(&mut self) {
         match self {
-            Value::Term(t) => t.fmt(f),
-            Value::Superpos(s) => s.fmt(f),
+            Value::Term(_) => (),
+            Value::Superpos(s) => s.merge(),
+            Value::Mixed(m) => m.merge(),
+        }
+    }
+
+    // Determines if two values are equal up to the names of bound variables in their terms.
+    pub fn alpha_eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Term(t1), Value::Term(t2)) => t1.alpha_eq(t2),
+            (Value::Superpos(s1), Value::Superpos(s2)) => {
+                s1.0.len() == s2.0.len()
+                    && s1
+                        .0
+                        .iter()
+                        .zip(&s2.0)
+                        .all(|((t1, a1), (t2, a2))| a1 == a2 && t1.alpha_eq(t2))
+            }
+            (Value::Mixed(m1), Value::Mixed(m2)) => {
+                m1.0.len() == m2.0.len()
+                    && m1
+                        .0
+                        .iter()
+                        .zip(&m2.0)
+                        .all(|((v1, p1), (v2, p2))| p1 == p2 && v1.alpha_eq(v2))
+            }
+            _ => false,
         }
     }
 }
 
-// The possible syntactic forms for terms in the AST.
-#[derive(Clone, Debug, PartialEq, Eq)]
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", pretty::value(self, pretty::WIDTH))
+    }
+}
+
+// The possible syntactic forms for terms in the AST. Terms can be nested far deeper than the call
+// stack allows, so they are cloned, compared, hashed, printed and dropped from explicit stacks.
 pub enum Term {
     Var(String),
     Const(Const),
@@ -37,7 +81,293 @@ pub enum Term {
     Nonlinear(Box<Term>),
 }
 
+// The term left in place of a subterm that has been moved out of its parent, which owns nothing
+// on the heap.
+const HOLE: Term = Term::Const(Const::Meas);
+
+// How deep cloning and dropping a term recurse before they carry on from an explicit stack. Most
+// terms are shallower than this, and are handled without allocating a stack at all.
+pub(crate) const DEPTH: usize = 64;
+
+impl Term {
+    // Drops the subterms of this term, recursing until the depth runs out and pushing the
+    // subterms below that onto the stack. Each subterm is left as a hole, so that dropping it
+    // afterwards does nothing more.
+    fn dispose(&mut self, depth: usize, stack: &mut Vec<Term>) {
+        let mut take = |t: &mut Box<Term>| {
+            if matches!(**t, Term::Var(_) | Term::Const(_)) {
+                return;
+            }
+            let mut t = std::mem::replace(&mut **t, HOLE);
+            match depth {
+                0 => stack.push(t),
+                _ => t.dispose(depth - 1, stack),
+            }
+        };
+        match self {
+            Term::Var(_) | Term::Const(_) => (),
+            Term::Abs(_, t) | Term::NonlinearAbs(_, t) | Term::Nonlinear(t) => take(t),
+            Term::App(t1, t2) => {
+                take(t1);
+                take(t2);
+            }
+        }
+    }
+
+    // Clones this term by recursion until the depth runs out, and from an explicit stack below
+    // that.
+    fn clone_within(&self, depth: usize) -> Term {
+        if depth == 0 {
+            return self.clone_deep();
+        }
+        let sub = |t: &Term| Box::new(t.clone_within(depth - 1));
+        match self {
+            Term::Var(x) => Term::Var(x.clone()),
+            Term::Const(c) => Term::Const(c.clone()),
+            Term::Abs(x, body) => Term::Abs(x.clone(), sub(body)),
+            Term::NonlinearAbs(x, body) => Term::NonlinearAbs(x.clone(), sub(body)),
+            Term::App(t1, t2) => Term::App(sub(t1), sub(t2)),
+            Term::Nonlinear(t) => Term::Nonlinear(sub(t)),
+        }
+    }
+
+    fn clone_deep(&self) -> Term {
+        enum Task<'a> {
+            Visit(&'a Term),
+            Abs(&'a str),
+            NonlinearAbs(&'a str),
+            App,
+            Nonlinear,
+        }
+
+        let mut stack = vec![Task::Visit(self)];
+        let mut out: Vec<Term> = Vec::new();
+        while let Some(task) = stack.pop() {
+            let mut pop = || Box::new(out.pop().expect("missing cloned subterm"));
+            let t = match task {
+                Task::Visit(t) => match t {
+                    Term::Var(x) => Term::Var(x.clone()),
+                    Term::Const(c) => Term::Const(c.clone()),
+                    Term::Abs(x, body) => {
+                        stack.extend([Task::Abs(x), Task::Visit(body)]);
+                        continue;
+                    }
+                    Term::NonlinearAbs(x, body) => {
+                        stack.extend([Task::NonlinearAbs(x), Task::Visit(body)]);
+                        continue;
+                    }
+                    Term::App(t1, t2) => {
+                        stack.extend([Task::App, Task::Visit(t2), Task::Visit(t1)]);
+                        continue;
+                    }
+                    Term::Nonlinear(t) => {
+                        stack.extend([Task::Nonlinear, Task::Visit(t)]);
+                        continue;
+                    }
+                },
+                Task::Abs(x) => Term::Abs(x.to_string(), pop()),
+                Task::NonlinearAbs(x) => Term::NonlinearAbs(x.to_string(), pop()),
+                Task::Nonlinear => Term::Nonlinear(pop()),
+                Task::App => {
+                    let t2 = pop();
+                    Term::App(pop(), t2)
+                }
+            };
+            out.push(t);
+        }
+        out.pop().expect("missing cloned term")
+    }
+
+    // Takes this term apart into its immediate subterms, since a term can't be moved out of by
+    // pattern matching.
+    pub fn into_node(mut self) -> Node {
+        let take = |t: &mut Box<Term>| std::mem::replace(&mut **t, HOLE);
+        match &mut self {
+            Term::Var(x) => Node::Var(std::mem::take(x)),
+            Term::Const(c) => Node::Const(std::mem::replace(c, Const::Meas)),
+            Term::Abs(x, t) => Node::Abs(std::mem::take(x), take(t)),
+            Term::NonlinearAbs(x, t) => Node::NonlinearAbs(std::mem::take(x), take(t)),
+            Term::App(t1, t2) => Node::App(take(t1), take(t2)),
+            Term::Nonlinear(t) => Node::Nonlinear(take(t)),
+        }
+    }
+}
+
+// A term taken apart into its immediate subterms, which can be moved out of it.
+pub enum Node {
+    Var(String),
+    Const(Const),
+    Abs(String, Term),
+    App(Term, Term),
+    NonlinearAbs(String, Term),
+    Nonlinear(Term),
+}
+
+impl Node {
+    // Puts a term back together from its immediate subterms.
+    pub fn into_term(self) -> Term {
+        match self {
+            Node::Var(x) => Term::Var(x),
+            Node::Const(c) => Term::Const(c),
+            Node::Abs(x, t) => Term::Abs(x, Box::new(t)),
+            Node::App(t1, t2) => Term::App(Box::new(t1), Box::new(t2)),
+            Node::NonlinearAbs(x, t) => Term::NonlinearAbs(x, Box::new(t)),
+            Node::Nonlinear(t) => Term::Nonlinear(Box::new(t)),
+        }
+    }
+}
+
+impl Drop for Term {
+    fn drop(&mut self) {
+        let mut stack = Vec::new();
+        self.dispose(DEPTH, &mut stack);
+        while let Some(mut t) = stack.pop() {
+            t.dispose(DEPTH, &mut stack);
+        }
+    }
+}
+
+impl Clone for Term {
+    fn clone(&self) -> Self {
+        self.clone_within(DEPTH)
+    }
+}
+
+impl PartialEq for Term {
+    fn eq(&self, other: &Self) -> bool {
+        let mut stack = vec![(self, other)];
+        while let Some(pair) = stack.pop() {
+            match pair {
+                (Term::Var(x), Term::Var(y)) if x == y => (),
+                (Term::Const(c), Term::Const(d)) if c == d => (),
+                (Term::Abs(x, t1), Term::Abs(y, t2))
+                | (Term::NonlinearAbs(x, t1), Term::NonlinearAbs(y, t2))
+                    if x == y =>
+                {
+                    stack.push((t1, t2))
+                }
+                (Term::App(t1, t2), Term::App(u1, u2)) => stack.extend([(&**t2, &**u2), (t1, u1)]),
+                (Term::Nonlinear(t), Term::Nonlinear(u)) => stack.push((t, u)),
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+impl Eq for Term {}
+
+impl Hash for Term {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let mut stack = vec![self];
+        while let Some(t) = stack.pop() {
+            std::mem::discriminant(t).hash(state);
+            match t {
+                Term::Var(x) => x.hash(state),
+                Term::Const(c) => c.hash(state),
+                Term::Abs(x, body) | Term::NonlinearAbs(x, body) => {
+                    x.hash(state);
+                    stack.push(body);
+                }
+                Term::App(t1, t2) => stack.extend([&**t2, &**t1]),
+                Term::Nonlinear(t) => stack.push(t),
+            }
+        }
+    }
+}
+
+// Terms are debugged in the form a derived Debug would give, as in App(Var("f"), Var("x")).
+impl std::fmt::Debug for Term {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        enum Item<'a> {
+            Term(&'a Term),
+            Text(&'static str),
+        }
+
+        let mut stack = vec![Item::Term(self)];
+        while let Some(item) = stack.pop() {
+            let t = match item {
+                Item::Term(t) => t,
+                Item::Text(s) => {
+                    f.write_str(s)?;
+                    continue;
+                }
+            };
+            match t {
+                Term::Var(x) => write!(f, "Var({x:?})")?,
+                Term::Const(c) => write!(f, "Const({c:?})")?,
+                Term::Abs(x, body) => {
+                    write!(f, "Abs({x:?}, ")?;
+                    stack.extend([Item::Text(")"), Item::Term(body)]);
+                }
+                Term::NonlinearAbs(x, body) => {
+                    write!(f, "NonlinearAbs({x:?}, ")?;
+                    stack.extend([Item::Text(")"), Item::Term(body)]);
+                }
+                Term::App(t1, t2) => {
+                    f.write_str("App(")?;
+                    stack.extend([
+                        Item::Text(")"),
+                        Item::Term(t2),
+                        Item::Text(", "),
+                        Item::Term(t1),
+                    ]);
+                }
+                Term::Nonlinear(t) => {
+                    f.write_str("Nonlinear(")?;
+                    stack.extend([Item::Text(")"), Item::Term(t)]);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
 impl Term {
+    // Determines if two terms are equal up to the names of their bound variables.
+    pub fn alpha_eq(&self, other: &Term) -> bool {
+        enum Task<'a> {
+            Visit(&'a Term, &'a Term),
+            Unbind,
+        }
+
+        // The binders in scope on each side, innermost last, which a pair of variables must
+        // either both refer to at the same depth or both be free of.
+        let mut scope: Vec<(&str, &str)> = Vec::new();
+        let mut stack = vec![Task::Visit(self, other)];
+        while let Some(task) = stack.pop() {
+            let (t1, t2) = match task {
+                Task::Visit(t1, t2) => (t1, t2),
+                Task::Unbind => {
+                    scope.pop();
+                    continue;
+                }
+            };
+            match (t1, t2) {
+                (Term::Var(x), Term::Var(y)) => {
+                    let bound = scope.iter().rposition(|(u, v)| u == x || v == y);
+                    match bound {
+                        Some(i) if scope[i] == (x.as_str(), y.as_str()) => (),
+                        None if x == y => (),
+                        _ => return false,
+                    }
+                }
+                (Term::Const(c), Term::Const(d)) if c == d => (),
+                (Term::Abs(x, b1), Term::Abs(y, b2))
+                | (Term::NonlinearAbs(x, b1), Term::NonlinearAbs(y, b2)) => {
+                    scope.push((x, y));
+                    stack.extend([Task::Unbind, Task::Visit(b1, b2)]);
+                }
+                (Term::App(t1, t2), Term::App(u1, u2)) => {
+                    stack.extend([Task::Visit(t2, u2), Task::Visit(t1, u1)])
+                }
+                (Term::Nonlinear(t), Term::Nonlinear(u)) => stack.push(Task::Visit(t, u)),
+                _ => return false,
+            }
+        }
+        true
+    }
+
     fn as_var(&self) -> Option<&str> {
         if let Term::Var(x) = self {
             Some(x)
@@ -62,42 +392,471 @@ impl Term {
         }
     }
 
-    fn as_pair(&self) -> Option<(&Term, &Term)> {
-        let (x, body) = self.as_abs()?;
-        let (left, b) = body.as_app()?;
-        let (right, a) = left.as_app()?;
+    // Recognises a church-encoded n-tuple \f.f t1 ... tn, giving its components.
+    fn as_tuple(&self, n: usize) -> Option<Vec<&Term>> {
+        let (x, mut body) = self.as_abs()?;
+        let mut elems = Vec::with_capacity(n);
+        for _ in 0..n {
+            let (rest, elem) = body.as_app()?;
+            elems.push(elem);
+            body = rest;
+        }
 
-        if let Some(v) = right.as_var()
-            && v == x {
-                return Some((a, b));
-            }
+        if body.as_var()? == x {
+            elems.reverse();
+            return Some(elems);
+        }
+        None
+    }
+
+    // Recognises a church-encoded tuple \f.f t1 ... tn of any size n >= 2, giving its components.
+    pub(crate) fn as_any_tuple(&self) -> Option<Vec<&Term>> {
+        let (x, mut body) = self.as_abs()?;
+        let mut elems = Vec::new();
+        while let Some((rest, elem)) = body.as_app() {
+            elems.push(elem);
+            body = rest;
+        }
+
+        if elems.len() >= 2 && body.as_var()? == x {
+            elems.reverse();
+            return Some(elems);
+        }
         None
     }
 
+    // Recognises a ket or a tuple of kets as the basis state it stands for, giving its bits.
+    pub(crate) fn as_basis(&self) -> Option<Vec<bool>> {
+        let bit = |t: &Term| match t {
+            Term::Const(Const::Ket(b)) => Some(*b),
+            _ => None,
+        };
+        match self.as_any_tuple() {
+            Some(elems) => elems.into_iter().map(bit).collect(),
+            None => bit(self).map(|b| vec![b]),
+        }
+    }
+
+    // Recognises a tuple \b.b t1 ... tn of values exactly as the parser builds it from the syntax
+    // (t1, ..., tn), with the variable the parser picks.
+    fn as_tuple_value(&self) -> Option<Vec<&Term>> {
+        let (x, _) = self.as_abs()?;
+        let elems = self.as_any_tuple()?;
+        let exact = elems.iter().all(|t| t.as_app().is_none()) && x == fresh(elems.iter().copied());
+        exact.then_some(elems)
+    }
+
+    // Recognises a tuple exactly as the parser builds it from the syntax (t1, ..., tn), so that it
+    // reads back as the very same term when printed that way. A tuple of values is \b.b t1 ... tn,
+    // while any other applies \x1 ... xn.(x1, ..., xn) to its elements, so that they are reduced
+    // first.
+    fn as_sugared_tuple(&self) -> Option<Vec<&Term>> {
+        if let Some(elems) = self.as_tuple_value() {
+            return Some(elems);
+        }
+
+        let is_value = |t: &&Term| t.as_app().is_none();
+        let mut args = Vec::new();
+        let mut head = self;
+        while let Some((f, arg)) = head.as_app() {
+            if args.len() == MAX_SUGARED_ARGS {
+                return None;
+            }
+            args.push(arg);
+            head = f;
+        }
+        if args.len() < 2 || args.iter().all(is_value) {
+            return None;
+        }
+        args.reverse();
+        let names: Vec<String> = (1..=args.len()).map(|i| format!("x{i}")).collect();
+        let mut body = head;
+        for name in &names {
+            let (x, rest) = body.as_abs()?;
+            if x != name {
+                return None;
+            }
+            body = rest;
+        }
+        let params = body.as_tuple_value()?;
+        let exact = params.len() == names.len()
+            && params
+                .iter()
+                .zip(&names)
+                .all(|(t, x)| t.as_var() == Some(x.as_str()));
+        exact.then_some(args)
+    }
+
+    // Recognises a boolean exactly as true and false are parsed, #x.#y.x for true or #x.#y.y for
+    // false.
+    pub(crate) fn as_bool(&self) -> Option<bool> {
+        let Term::NonlinearAbs(x, body) = self else {
+            return None;
+        };
+        let Term::NonlinearAbs(y, body) = &**body else {
+            return None;
+        };
+        if x != "x" || y != "y" {
+            return None;
+        }
+        match body.as_var()? {
+            "x" => Some(true),
+            "y" => Some(false),
+            _ => None,
+        }
+    }
+
+    // Recognises a nonempty list [t1, ..., tn] in the shape the parser builds it, as nested pairs
+    // (t1, (t2, ... (tn, false))), giving its elements.
+    fn as_list(&self) -> Option<Vec<&Term>> {
+        let mut elems = Vec::new();
+        let mut t = self;
+        while t.as_bool() != Some(false) {
+            let [head, tail] = t.as_sugared_tuple()?[..] else {
+                return None;
+            };
+            elems.push(head);
+            t = tail;
+        }
+        (!elems.is_empty()).then_some(elems)
+    }
+
+    // Determines if the variable occurs free in this term.
+    pub(crate) fn has_free_var(&self, x: &str) -> bool {
+        let mut stack = vec![self];
+        while let Some(t) = stack.pop() {
+            match t {
+                Term::Var(y) if y == x => return true,
+                Term::Var(_) | Term::Const(_) => (),
+                Term::Abs(y, _) | Term::NonlinearAbs(y, _) if y == x => (),
+                Term::Abs(_, body) | Term::NonlinearAbs(_, body) | Term::Nonlinear(body) => {
+                    stack.push(body)
+                }
+                Term::App(t1, t2) => stack.extend([&**t2, &**t1]),
+            }
+        }
+        false
+    }
+
+    // Counts the free occurrences of the variable in this term.
+    fn occurrences(&self, x: &str) -> usize {
+        let mut n = 0;
+        let mut stack = vec![self];
+        while let Some(t) = stack.pop() {
+            match t {
+                Term::Var(y) if y == x => n += 1,
+                Term::Var(_) | Term::Const(_) => (),
+                Term::Abs(y, _) | Term::NonlinearAbs(y, _) if y == x => (),
+                Term::Abs(_, body) | Term::NonlinearAbs(_, body) | Term::Nonlinear(body) => {
+                    stack.push(body)
+                }
+                Term::App(t1, t2) => stack.extend([&**t2, &**t1]),
+            }
+        }
+        n
+    }
+
+    // Gets the names of the variables occurring free in this term.
+    pub(crate) fn free_vars(&self) -> HashSet<&str> {
+        enum Task<'a> {
+            Visit(&'a Term),
+            Unbind(&'a str),
+        }
+
+        let mut free = HashSet::new();
+        let mut bound: HashMap<&str, usize> = HashMap::new();
+        let mut stack = vec![Task::Visit(self)];
+        while let Some(task) = stack.pop() {
+            match task {
+                Task::Visit(Term::Var(x)) => {
+                    if bound.get(x.as_str()).is_none_or(|n| *n == 0) {
+                        free.insert(x.as_str());
+                    }
+                }
+                Task::Visit(Term::Const(_)) => (),
+                Task::Visit(Term::Abs(x, body) | Term::NonlinearAbs(x, body)) => {
+                    *bound.entry(x).or_default() += 1;
+                    stack.push(Task::Unbind(x));
+                    stack.push(Task::Visit(body));
+                }
+                Task::Visit(Term::App(t1, t2)) => {
+                    stack.push(Task::Visit(t2));
+                    stack.push(Task::Visit(t1));
+                }
+                Task::Visit(Term::Nonlinear(t)) => stack.push(Task::Visit(t)),
+                Task::Unbind(x) => {
+                    if let Some(n) = bound.get_mut(x) {
+                        *n -= 1;
+                    }
+                }
+            }
+        }
+        free
+    }
+
+    // Gets every name in this term, whether of a variable or a binder.
+    fn names(&self) -> HashSet<String> {
+        let mut names = HashSet::new();
+        let mut stack = vec![self];
+        while let Some(t) = stack.pop() {
+            match t {
+                Term::Var(x) => {
+                    names.insert(x.clone());
+                }
+                Term::Const(_) => (),
+                Term::Abs(x, body) | Term::NonlinearAbs(x, body) => {
+                    names.insert(x.clone());
+                    stack.push(body);
+                }
+                Term::App(t1, t2) => stack.extend([&**t2, &**t1]),
+                Term::Nonlinear(t) => stack.push(t),
+            }
+        }
+        names
+    }
+
     pub fn to_classical(self) -> Term {
+        self.map_consts(|c| match c {
+            Const::Ket(b) => var(if b { "true" } else { "false" }),
+            c => Term::Const(c),
+        })
+    }
+
+    // Rebuilds this term with each constant replaced by the term the function gives for it.
+    pub(crate) fn map_consts<F: FnMut(Const) -> Term>(self, mut f: F) -> Term {
+        enum Task {
+            Visit(Term),
+            Abs(String),
+            NonlinearAbs(String),
+            App,
+            Nonlinear,
+        }
+
+        let mut stack = vec![Task::Visit(self)];
+        let mut out = Vec::new();
+        while let Some(task) = stack.pop() {
+            let mut pop = || out.pop().expect("missing rebuilt subterm");
+            let t = match task {
+                Task::Visit(t) => match t.into_node() {
+                    Node::Const(c) => f(c),
+                    Node::Var(x) => Term::Var(x),
+                    Node::Abs(x, body) => {
+                        stack.extend([Task::Abs(x), Task::Visit(body)]);
+                        continue;
+                    }
+                    Node::NonlinearAbs(x, body) => {
+                        stack.extend([Task::NonlinearAbs(x), Task::Visit(body)]);
+                        continue;
+                    }
+                    Node::App(t1, t2) => {
+                        stack.extend([Task::App, Task::Visit(t2), Task::Visit(t1)]);
+                        continue;
+                    }
+                    Node::Nonlinear(t) => {
+                        stack.extend([Task::Nonlinear, Task::Visit(t)]);
+                        continue;
+                    }
+                },
+                Task::Abs(x) => abs(&x, pop()),
+                Task::NonlinearAbs(x) => nonlinear_abs(&x, pop()),
+                Task::Nonlinear => nonlinear(pop()),
+                Task::App => {
+                    let t2 = pop();
+                    app(pop(), t2)
+                }
+            };
+            out.push(t);
+        }
+        out.pop().expect("missing rebuilt term")
+    }
+}
+
+// The most elements a tuple holding applications may have to be printed with tuple syntax, which
+// keeps every application of a long spine from being searched for one.
+const MAX_SUGARED_ARGS: usize = 64;
+
+// Where a term is printed, which decides whether it needs parentheses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Position {
+    // At the end of a scope, such as the whole term, the body of an abstraction or a component of
+    // a tuple, where nothing follows it.
+    Open,
+    // The function of an application.
+    Func,
+    // The argument of an application.
+    Arg,
+}
+
+impl Term {
+    // Recognises a tuple or list, giving the brackets it is printed between and its elements.
+    pub(crate) fn as_sugared(&self) -> Option<(&'static str, Vec<&Term>, &'static str)> {
         match self {
-            Term::Const(Const::Ket(b)) => {
-                if b {
-                    var("true")
-                } else {
-                    var("false")
+            Term::Abs(_, _) | Term::App(_, _) => self
+                .as_list()
+                .map(|elems| ("[", elems, "]"))
+                .or_else(|| self.as_sugared_tuple().map(|elems| ("(", elems, ")"))),
+            _ => None,
+        }
+    }
+
+    // Determines if this term needs parentheses when printed in the given position. Applications
+    // associate to the left, so only need them as arguments, while abstractions are bracketed
+    // anywhere but at the end of a scope, as their bodies run as far right as they can.
+    pub(crate) fn needs_parens(&self, pos: Position) -> bool {
+        if self.as_sugared().is_some() || self.as_bool().is_some() {
+            return false;
+        }
+        match self {
+            Term::App(_, _) => pos == Position::Arg,
+            Term::Abs(_, _) | Term::NonlinearAbs(_, _) => pos != Position::Open,
+            Term::Var(_) | Term::Const(_) | Term::Nonlinear(_) => false,
+        }
+    }
+
+    // Gets the variables bound by a run of abstractions of the same kind at the top of this term,
+    // printed together as in λx y. body, along with the body.
+    pub(crate) fn binders(&self) -> (Vec<&str>, &Term) {
+        let mut xs = Vec::new();
+        let mut t = self;
+        loop {
+            let next = match t {
+                Term::Abs(x, body) if matches!(self, Term::Abs(_, _)) => (x, body),
+                Term::NonlinearAbs(x, body) if matches!(self, Term::NonlinearAbs(_, _)) => {
+                    (x, body)
                 }
+                _ => break,
+            };
+            if !xs.is_empty() && (t.as_sugared().is_some() || t.as_bool().is_some()) {
+                break;
             }
-            Term::Const(_) | Term::Var(_) => self,
-            Term::Abs(x, body) => abs(&x, body.to_classical()),
-            Term::App(t1, t2) => app(t1.to_classical(), t2.to_classical()),
-            Term::NonlinearAbs(x, body) => nonlinear_abs(&x, body.to_classical()),
-            Term::Nonlinear(t) => nonlinear(t.to_classical()),
+            xs.push(next.0.as_str());
+            t = next.1;
         }
+        (xs, t)
     }
 }
 
 impl std::fmt::Display for Term {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Terms are printed from an explicit stack, since they can be nested far deeper than the
+        // call stack allows.
+        enum Item<'a> {
+            Term(&'a Term, Position),
+            Text(&'static str),
+        }
+
+        let mut stack = vec![Item::Term(self, Position::Open)];
+        while let Some(item) = stack.pop() {
+            let (t, pos) = match item {
+                Item::Term(t, pos) => (t, pos),
+                Item::Text(s) => {
+                    f.write_str(s)?;
+                    continue;
+                }
+            };
+            if t.needs_parens(pos) {
+                f.write_str("(")?;
+                stack.push(Item::Text(")"));
+            }
+
+            // Tuples, lists and booleans are printed in the syntax that builds them.
+            if let Some((open, elems, close)) = t.as_sugared() {
+                f.write_str(open)?;
+                stack.push(Item::Text(close));
+                for (i, elem) in elems.into_iter().enumerate().rev() {
+                    stack.push(Item::Term(elem, Position::Open));
+                    if i > 0 {
+                        stack.push(Item::Text(", "));
+                    }
+                }
+                continue;
+            }
+
+            match t {
+                Term::Var(x) => write!(f, "{}", identifier(x))?,
+                Term::Const(c) => write!(f, "{c}")?,
+                Term::NonlinearAbs(_, _) if let Some(b) = t.as_bool() => write!(f, "{b}")?,
+                Term::Abs(_, _) | Term::NonlinearAbs(_, _) => {
+                    let (xs, body) = t.binders();
+                    let lam = if matches!(t, Term::Abs(_, _)) {
+                        "λ"
+                    } else {
+                        "#"
+                    };
+                    let xs: Vec<_> = xs.into_iter().map(identifier).collect();
+                    write!(f, "{lam}{}. ", xs.join(" "))?;
+                    stack.push(Item::Term(body, Position::Open));
+                }
+                Term::App(a, b) => {
+                    stack.extend([
+                        Item::Term(b, Position::Arg),
+                        Item::Text(" "),
+                        Item::Term(a, Position::Func),
+                    ]);
+                }
+                Term::Nonlinear(t) => {
+                    f.write_str("!(")?;
+                    stack.extend([Item::Text(")"), Item::Term(t, Position::Open)]);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Gates and rotations carry the position of their site in a pre-order traversal of the evaluated
+// term, if they come from it, so that errors in applying them can be pointed out there.
+#[derive(Clone, Debug)]
+pub enum Const {
+    Ket(bool),
+    Gate(String, Option<usize>),
+    Rotation(Rotation, f64, Option<usize>),
+    Meas,
+    // A reference to a qubit held in the register of the dense backend.
+    Qubit(usize),
+}
+
+// Constants are compared syntactically, so rotation angles are equal when their bits are, and
+// wherever they come from.
+impl PartialEq for Const {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Const::Ket(a), Const::Ket(b)) => a == b,
+            (Const::Gate(a, _), Const::Gate(b, _)) => a == b,
+            (Const::Rotation(r1, a, _), Const::Rotation(r2, b, _)) => {
+                r1 == r2 && a.to_bits() == b.to_bits()
+            }
+            (Const::Meas, Const::Meas) => true,
+            (Const::Qubit(a), Const::Qubit(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Const {}
+
+impl Hash for Const {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Const::Ket(b) => b.hash(state),
+            Const::Gate(g, _) => g.hash(state),
+            Const::Rotation(r, theta, _) => {
+                r.hash(state);
+                theta.to_bits().hash(state);
+            }
+            Const::Meas => (),
+            Const::Qubit(q) => q.hash(state),
+        }
+    }
+}
+
+impl Display for Const {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Term::Var(x) => write!(f, "{x}"),
-            Term::Const(Const::Gate(g)) => write!(f, "{g}"),
-            Term::Const(Const::Ket(b)) => {
+            Const::Gate(g, _) => write!(f, "{g}"),
+            Const::Rotation(r, theta, _) => write!(f, "{r}({theta})"),
+            Const::Ket(b) => {
                 write!(f, "|")?;
                 if *b {
                     write!(f, "1")?;
@@ -106,195 +865,310 @@ impl std::fmt::Display for Term {
                 }
                 write!(f, ">")
             }
-            Term::Const(Const::Meas) => {
+            Const::Meas => {
                 write!(f, "M")
             }
-            Term::Abs(x, body) => write!(f, "(λ{x}. {body})"),
-            Term::App(a, b) => write!(f, "({a} {b})"),
-            Term::NonlinearAbs(x, body) => write!(f, "(#{x}. {body})"),
-            Term::Nonlinear(t) => write!(f, "!({t})"),
+            Const::Qubit(q) => write!(f, "|q{q}>"),
         }
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
-pub enum Const {
-    Ket(bool),
-    Gate(String),
-    Meas,
+// The single-qubit gates parameterised by an angle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Rotation {
+    Rx,
+    Ry,
+    Rz,
+    Phase,
 }
 
-// Gets the free variables of a lambda term.
-fn free_vars(t: &Term) -> HashSet<String> {
-    match t {
-        Term::Var(x) => [x.clone()].into_iter().collect(),
-        Term::Const(_) => empty().collect(),
-        Term::Abs(x, body) => &free_vars(body) - &[x.clone()].into_iter().collect(),
-        Term::App(a, b) => &free_vars(a) | &free_vars(b),
-        Term::NonlinearAbs(x, body) => &free_vars(body) - &[x.clone()].into_iter().collect(),
-        Term::Nonlinear(t) => free_vars(t),
+impl Rotation {
+    // Gets the rotation with the given name, if there is one.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "Rx" => Some(Rotation::Rx),
+            "Ry" => Some(Rotation::Ry),
+            "Rz" => Some(Rotation::Rz),
+            "Phase" => Some(Rotation::Phase),
+            _ => None,
+        }
+    }
+
+    // Gets the image of the basis state |b> under this rotation by theta, given as the amplitudes
+    // of |0> and |1>.
+    fn column(self, theta: f64, b: bool) -> [Complex<f64>; 2] {
+        let zero = Complex::new(0.0, 0.0);
+        let (c, s) = ((theta / 2.0).cos(), (theta / 2.0).sin());
+        match (self, b) {
+            (Rotation::Rx, false) => [Complex::new(c, 0.0), Complex::new(0.0, -s)],
+            (Rotation::Rx, true) => [Complex::new(0.0, -s), Complex::new(c, 0.0)],
+            (Rotation::Ry, false) => [Complex::new(c, 0.0), Complex::new(s, 0.0)],
+            (Rotation::Ry, true) => [Complex::new(-s, 0.0), Complex::new(c, 0.0)],
+            (Rotation::Rz, false) => [Complex::new(0.0, -theta / 2.0).exp(), zero],
+            (Rotation::Rz, true) => [zero, Complex::new(0.0, theta / 2.0).exp()],
+            (Rotation::Phase, false) => [Complex::new(1.0, 0.0), zero],
+            (Rotation::Phase, true) => [zero, Complex::new(0.0, theta).exp()],
+        }
     }
 }
 
-// Finds a fresh variable using the given base string and bound variables.
-fn fresh_var(base: &str, in_use: &HashSet<String>) -> String {
-    let mut n = 0;
-    let mut name = base.to_string();
-    while in_use.contains(&name) {
-        n += 1;
-        name = format!("{base}{n}");
+impl Display for Rotation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Rotation::Rx => write!(f, "Rx"),
+            Rotation::Ry => write!(f, "Ry"),
+            Rotation::Rz => write!(f, "Rz"),
+            Rotation::Phase => write!(f, "Phase"),
+        }
     }
-    name
 }
 
 // Determines if a term contains a ket |0> or |1>.
 fn contains_ket(t: &Term) -> bool {
-    match t {
-        Term::Var(_) => false,
-        Term::Const(Const::Ket(_)) => true,
-        Term::Const(_) => false,
-        Term::Abs(_, body) => contains_ket(body),
-        Term::NonlinearAbs(_, body) => contains_ket(body),
-        Term::App(t1, t2) => contains_ket(t1) || contains_ket(t2),
-        Term::Nonlinear(t) => contains_ket(t),
+    let mut stack = vec![t];
+    while let Some(t) = stack.pop() {
+        match t {
+            Term::Var(_) => {}
+            Term::Const(Const::Ket(_) | Const::Qubit(_)) => return true,
+            Term::Const(_) => {}
+            Term::Abs(_, body) => stack.push(body),
+            Term::NonlinearAbs(_, body) => stack.push(body),
+            Term::App(t1, t2) => stack.extend([t2.as_ref(), t1.as_ref()]),
+            Term::Nonlinear(t) => stack.push(t),
+        }
     }
+    false
 }
 
 // Determines if a term is well-formed; that is, all free variables in nonlinear suspensions refer
-// to nonlinear variables in an outer lambda.
-fn well_formed(t: &Term) -> Result<(), String> {
+// to nonlinear variables in an outer lambda. An error gives the offending subterm by its position
+// in a pre-order traversal of the term.
+fn well_formed(t: &Term) -> Result<(), (String, usize)> {
     #[derive(Clone, Copy, Debug)]
     enum VarKind {
         Linear(usize),
         Nonlinear,
     }
 
-    fn check(term: &Term, vars: &mut HashMap<String, VarKind>) -> Result<(), String> {
-        match term {
-            Term::Var(x) => match vars.get_mut(x) {
-                Some(VarKind::Linear(count)) => {
+    // The work remaining in the check, kept on an explicit stack so that deeply nested terms can't
+    // overflow the call stack.
+    enum Task<'a> {
+        Check(&'a Term),
+        // Leaves the scope of the binder at the given node, restoring any binding it shadowed.
+        Unbind(&'a str, Option<VarKind>, usize),
+        // Leaves the nonlinear suspension at the given node, restoring the variables as they
+        // were outside it.
+        Resume(HashMap<String, VarKind>, usize),
+    }
+
+    let mut vars = HashMap::new();
+    let mut stack = vec![Task::Check(t)];
+    let mut next = 0;
+    while let Some(task) = stack.pop() {
+        let at = next;
+        if let Task::Check(_) = task {
+            next += 1;
+        }
+        match task {
+            Task::Check(Term::Var(x)) => {
+                if let Some(VarKind::Linear(count)) = vars.get_mut(x) {
                     *count += 1;
-                    Ok(())
                 }
-                _ => Ok(()),
-            },
+            }
 
-            Term::Const(_) => Ok(()),
+            Task::Check(Term::Const(_)) => {}
 
-            Term::Abs(x, body) => {
+            Task::Check(Term::Abs(x, body)) => {
                 // save old binding if shadowed
                 let old = vars.insert(x.clone(), VarKind::Linear(0));
-                check(body, vars)?;
-                match vars.remove(x) {
-                    Some(VarKind::Linear(0)) => Err(format!("linear variable {x} unused")),
-                    Some(VarKind::Linear(1)) => Ok(()),
-                    Some(VarKind::Linear(n)) => Err(format!("linear variable {x} used {n} times")),
-                    _ => unreachable!(),
-                }?;
-                // restore old binding
-                if let Some(v) = old {
-                    vars.insert(x.clone(), v);
-                }
-                Ok(())
+                stack.push(Task::Unbind(x, old, at));
+                stack.push(Task::Check(body));
             }
 
-            Term::NonlinearAbs(x, body) => {
+            Task::Check(Term::NonlinearAbs(x, body)) => {
                 let old = vars.insert(x.clone(), VarKind::Nonlinear);
-                check(body, vars)?;
-                vars.remove(x);
-                if let Some(v) = old {
-                    vars.insert(x.clone(), v);
-                }
-                Ok(())
+                stack.push(Task::Unbind(x, old, at));
+                stack.push(Task::Check(body));
             }
 
-            Term::App(f, a) => {
-                check(f, vars)?;
-                check(a, vars)
+            Task::Check(Term::App(f, a)) => {
+                stack.push(Task::Check(a));
+                stack.push(Task::Check(f));
             }
 
-            Term::Nonlinear(t) => {
+            Task::Check(Term::Nonlinear(t)) => {
                 if contains_ket(t) {
-                    return Err("ket appears inside !".to_string());
+                    return Err(("ket appears inside !".to_string(), at));
+                }
+
+                stack.push(Task::Resume(vars.clone(), at));
+                stack.push(Task::Check(t));
+            }
+
+            Task::Unbind(x, old, at) => {
+                match vars.remove(x) {
+                    Some(VarKind::Linear(0)) => {
+                        return Err((format!("linear variable {x} unused"), at));
+                    }
+                    Some(VarKind::Linear(1)) | Some(VarKind::Nonlinear) => {}
+                    Some(VarKind::Linear(n)) => {
+                        return Err((format!("linear variable {x} used {n} times"), at));
+                    }
+                    None => unreachable!(),
                 }
+                // restore old binding
+                if let Some(v) = old {
+                    vars.insert(x.to_string(), v);
+                }
+            }
 
-                let mut inner = vars.clone();
-                check(t, &mut inner)?;
-                for (x, kind) in inner {
+            Task::Resume(outer, at) => {
+                for (x, kind) in &vars {
                     if let VarKind::Linear(n) = kind
-                        && n > 0 {
-                            return Err(format!("linear variable {x} appears inside !"));
-                        }
+                        && *n > 0
+                    {
+                        return Err((format!("linear variable {x} appears inside !"), at));
+                    }
                 }
-                Ok(())
+                vars = outer;
             }
         }
     }
 
-    check(t, &mut HashMap::new())
+    Ok(())
 }
 
-// Safely substitues t[x -> s].
-fn subst(t: &Term, x: &str, s: &Term) -> Result<Term, EvalError> {
-    // The recursive procedure for substitution.
-    fn subst_helper(t: &Term, x: &str, s: &Term) -> Term {
-        match t {
-            Term::Var(y) => {
-                if x == y {
-                    s.clone()
-                } else {
-                    t.clone()
+// Safely substitutes t[x -> s] in a single pass over t. A binder of t is only renamed when x
+// occurs free under it and s has a free variable of its name, which it would otherwise capture, so
+// every other variable keeps the name it was written with. The last occurrence of x takes s itself
+// rather than a copy, so a large argument used once is never copied.
+fn subst(t: &Term, x: &str, s: Term) -> Result<Term, EvalError> {
+    enum Task<'a> {
+        Visit(&'a Term),
+        // Rebuilds an abstraction once its body is on the output stack, taking its binder out of
+        // scope.
+        Abs,
+        NonlinearAbs,
+        App,
+        Nonlinear,
+    }
+
+    // The free variables of s, and every name used in t or s, which a renamed binder must avoid.
+    // Both are only gathered once they are needed.
+    let mut free: Option<HashSet<String>> = None;
+    let mut taken: Option<HashSet<String>> = None;
+    // The binders in scope, innermost last, with the name each was renamed to if any, and how
+    // many of them bind x.
+    let mut uses = t.occurrences(x);
+    let mut s = Some(s);
+    let mut scope: Vec<(&str, Option<String>)> = Vec::new();
+    let mut shadowed = 0;
+    let mut stack = vec![Task::Visit(t)];
+    let mut out = Vec::new();
+    while let Some(task) = stack.pop() {
+        match task {
+            Task::Visit(u) => match u {
+                Term::Var(y) => out.push(match scope.iter().rfind(|(z, _)| z == y) {
+                    Some((_, Some(name))) => var(name),
+                    Some((_, None)) => u.clone(),
+                    None if y == x => {
+                        uses -= 1;
+                        match uses {
+                            0 => s.take().expect("x occurs no more times than counted"),
+                            _ => s.clone().expect("x occurs no more times than counted"),
+                        }
+                    }
+                    None => u.clone(),
+                }),
+                Term::Const(_) => out.push(u.clone()),
+                Term::Abs(y, body) | Term::NonlinearAbs(y, body) => {
+                    // Once s has been moved into the last occurrence of x, no binder left can
+                    // capture anything.
+                    let captures = y != x
+                        && shadowed == 0
+                        && uses > 0
+                        && free
+                            .get_or_insert_with(|| {
+                                s.iter()
+                                    .flat_map(Term::free_vars)
+                                    .map(String::from)
+                                    .collect()
+                            })
+                            .contains(y.as_str())
+                        && body.has_free_var(x);
+                    let rename = captures.then(|| {
+                        let taken = taken.get_or_insert_with(|| {
+                            let mut names = t.names();
+                            names.extend(s.iter().flat_map(Term::names));
+                            names
+                        });
+                        let name = (1..)
+                            .map(|n| format!("{y}{n}"))
+                            .find(|name| !taken.contains(name))
+                            .expect("some name is unused");
+                        taken.insert(name.clone());
+                        name
+                    });
+                    if y == x {
+                        shadowed += 1;
+                    }
+                    scope.push((y, rename));
+                    stack.push(match u {
+                        Term::Abs(_, _) => Task::Abs,
+                        _ => Task::NonlinearAbs,
+                    });
+                    stack.push(Task::Visit(body));
                 }
-            }
-            Term::Const(_) => t.clone(),
-            Term::Abs(y, body) => {
-                // shadowed case
-                if y == x {
-                    t.clone()
-                } else if free_vars(s).contains(y) {
-                    let used: HashSet<_> = free_vars(body).union(&free_vars(s)).cloned().collect();
-                    let fresh = fresh_var(y, &used);
-                    let renamed_body = subst_helper(body, y, &Term::Var(fresh.clone()));
-                    abs(&fresh, subst_helper(&renamed_body, x, s))
-                } else {
-                    abs(y, subst_helper(body, x, s))
+                Term::App(t1, t2) => {
+                    stack.push(Task::App);
+                    stack.push(Task::Visit(t2));
+                    stack.push(Task::Visit(t1));
                 }
-            }
-            Term::NonlinearAbs(y, body) => {
-                // shadowed case
+                Term::Nonlinear(t) => {
+                    stack.push(Task::Nonlinear);
+                    stack.push(Task::Visit(t));
+                }
+            },
+            Task::Abs | Task::NonlinearAbs => {
+                let (y, rename) = scope.pop().expect("binder not in scope");
                 if y == x {
-                    t.clone()
-                } else if free_vars(s).contains(y) {
-                    let used: HashSet<_> = free_vars(body).union(&free_vars(s)).cloned().collect();
-                    let fresh = fresh_var(y, &used);
-                    let renamed_body = subst_helper(body, y, &Term::Var(fresh.clone()));
-                    nonlinear_abs(&fresh, subst_helper(&renamed_body, x, s))
-                } else {
-                    nonlinear_abs(y, subst_helper(body, x, s))
+                    shadowed -= 1;
                 }
+                let body = out.pop().expect("missing substituted subterm");
+                let name = rename.as_deref().unwrap_or(y);
+                out.push(match task {
+                    Task::Abs => abs(name, body),
+                    _ => nonlinear_abs(name, body),
+                });
+            }
+            Task::App => {
+                let t2 = out.pop().expect("missing substituted subterm");
+                let t1 = out.pop().expect("missing substituted subterm");
+                out.push(app(t1, t2));
+            }
+            Task::Nonlinear => {
+                let t = out.pop().expect("missing substituted subterm");
+                out.push(nonlinear(t));
             }
-            Term::App(a, b) => app(subst_helper(a, x, s), subst_helper(b, x, s)),
-            Term::Nonlinear(t2) => nonlinear(subst_helper(t2, x, s)),
         }
     }
-
-    Ok(subst_helper(t, x, s))
+    Ok(out.pop().expect("missing substituted term"))
 }
 
 // Performs a classical beta reduction of two terms
 fn beta_reduce(t1: Term, t2: Term) -> Result<Term, EvalError> {
     match &t1 {
-        Term::Abs(x, body) => Ok(subst(body, x, &t2)?),
-        Term::NonlinearAbs(x, body) => match &t2 {
-            Term::Nonlinear(t) => Ok(subst(body, x, t)?),
-            _ => Err(EvalError::BadApplication(format!(
-                "Failure to beta-reduce nonlinear application {} {}: RHS was linear",
-                t1, t2
+        Term::Abs(x, body) => Ok(subst(body, x, t2)?),
+        Term::NonlinearAbs(x, body) => match t2.into_node() {
+            Node::Nonlinear(t) => Ok(subst(body, x, t)?),
+            t2 => Err(EvalError::BadApplication(format!(
+                "Failure to beta-reduce nonlinear application {}: RHS was linear",
+                app(t1.clone(), t2.into_term())
             ))),
         },
         _ => Err(EvalError::BadApplication(format!(
-            "Failure to beta-reduce application {} {}: LHS was not a lambda",
-            t1, t2
+            "Failure to beta-reduce application {}: LHS was not a lambda",
+            app(t1.clone(), t2)
         ))),
     }
 }
@@ -302,106 +1176,1079 @@ fn beta_reduce(t1: Term, t2: Term) -> Result<Term, EvalError> {
 #[derive(Debug, Clone)]
 pub enum EvalError {
     BadApplication(String),
-    BadGate(String),
+    // A gate was applied to the wrong number of qubits, at the site of the gate in a pre-order
+    // traversal of the evaluated term if it comes from the term itself.
+    BadGate(String, Option<usize>),
     UndefinedSymbol(String),
-    LinearityViolation(String),
+    // A variable was used the wrong number of times, at the given position in a pre-order traversal
+    // of the evaluated term if the violation was in the term itself rather than one it reduced to.
+    LinearityViolation(String, Option<usize>),
+    // The step or time limit ran out, leaving the term as far as it had been reduced.
+    OutOfFuel(Value),
+    // Evaluation was interrupted from outside, as by Ctrl-C in the REPL.
+    Interrupted(Value),
+    // The term was rejected by the type checker before evaluation.
+    TypeError(TypeError),
+}
+
+impl Display for EvalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EvalError::BadApplication(e)
+            | EvalError::BadGate(e, _)
+            | EvalError::LinearityViolation(e, _) => write!(f, "{e}"),
+            EvalError::UndefinedSymbol(x) => write!(f, "undefined symbol {x}"),
+            EvalError::OutOfFuel(v) => write!(f, "out of fuel after reducing to\n{v}"),
+            EvalError::Interrupted(v) => write!(f, "interrupted after reducing to\n{v}"),
+            EvalError::TypeError(e) => write!(f, "{e}"),
+        }
+    }
 }
 
-// Applies the given quantum gate to the ket
-fn apply_gate(g: &str, t: &Term) -> Result<Value, EvalError> {
+impl EvalError {
+    // Places the partially reduced term of a stopped evaluation into the context it was
+    // evaluated in, as when a branch of a superposition stops.
+    pub(crate) fn within<F: FnOnce(Value) -> Value>(self, context: F) -> Self {
+        match self {
+            EvalError::OutOfFuel(v) => EvalError::OutOfFuel(context(v)),
+            EvalError::Interrupted(v) => EvalError::Interrupted(context(v)),
+            e => e,
+        }
+    }
+}
+
+// Builds the application of one value to another, distributing over their branches, to show
+// where an evaluation stopped.
+pub(crate) fn app_values(v1: Value, v2: Value) -> Value {
+    // Applies one pure state to another, since no component of a mixture is itself a mixture.
+    let pure = |v1, v2| {
+        let branches = |v| match v {
+            Value::Superpos(s) => s,
+            Value::Term(t) => Superpos::trivial(t),
+            Value::Mixed(_) => unreachable!("no component of a mixture is a mixture"),
+        };
+        match (v1, v2) {
+            (Value::Term(t1), Value::Term(t2)) => Value::Term(app(t1, t2)),
+            (v1, v2) => {
+                let (s1, s2) = (branches(v1), branches(v2));
+                let mut out = Vec::with_capacity(s1.0.len() * s2.0.len());
+                for (t1, a1) in &s1.0 {
+                    for (t2, a2) in &s2.0 {
+                        out.push((app(t1.clone(), t2.clone()), a1 * a2));
+                    }
+                }
+                Value::Superpos(Superpos(out))
+            }
+        }
+    };
+
+    let components = |v| match v {
+        Value::Mixed(m) => m.0,
+        v => vec![(v, 1.0)],
+    };
+    match (v1, v2) {
+        (v1 @ Value::Mixed(_), v2) | (v1, v2 @ Value::Mixed(_)) => {
+            let (m1, m2) = (components(v1), components(v2));
+            let mut out = Vec::with_capacity(m1.len() * m2.len());
+            for (v1, p1) in &m1 {
+                for (v2, p2) in &m2 {
+                    out.push((pure(v1.clone(), v2.clone()), p1 * p2));
+                }
+            }
+            Value::Mixed(Mixture(out))
+        }
+        (v1, v2) => pure(v1, v2),
+    }
+}
+
+// Rebuilds every term in a value, keeping its amplitudes and probabilities.
+fn map_value<F: Fn(Term) -> Term>(v: Value, f: &F) -> Value {
+    // Rebuilds a pure state, since no component of a mixture is itself a mixture.
+    let pure = |v| match v {
+        Value::Term(t) => Value::Term(f(t)),
+        Value::Superpos(s) => Value::Superpos(Superpos(
+            s.0.into_iter().map(|(t, amp)| (f(t), amp)).collect(),
+        )),
+        Value::Mixed(_) => unreachable!("no component of a mixture is a mixture"),
+    };
+    match v {
+        Value::Mixed(m) => Value::Mixed(Mixture(
+            m.0.into_iter().map(|(v, p)| (pure(v), p)).collect(),
+        )),
+        v => pure(v),
+    }
+}
+
+// The names of the built-in quantum gates.
+pub const GATES: &[&str] = &[
+    "H", "C", "T", "X", "Y", "Z", "S", "Sdg", "Tdg", "CZ", "SWAP", "CCX",
+];
+
+// Determines if a constant is a gate or measurement, which act on the qubits of their argument.
+pub(crate) fn is_primitive(c: &Const) -> bool {
+    matches!(
+        c,
+        Const::Gate(_, _) | Const::Rotation(_, _, _) | Const::Meas
+    )
+}
+
+// Gets the number of qubits a built-in gate acts on.
+fn builtin_arity(g: &str) -> Option<usize> {
+    match g {
+        "H" | "T" | "X" | "Y" | "Z" | "S" | "Sdg" | "Tdg" => Some(1),
+        "C" | "CZ" | "SWAP" => Some(2),
+        "CCX" => Some(3),
+        _ => None,
+    }
+}
+
+// Gets the image of the basis state |b> under a single-qubit gate, given as the amplitudes of |0>
+// and |1>.
+fn single_qubit_gate(g: &str, b: bool) -> [Complex<f64>; 2] {
+    let zero = Complex::new(0.0, 0.0);
+    let one = Complex::new(1.0, 0.0);
+    let i = Complex::new(0.0, 1.0);
+    let minus_i = Complex::new(0.0, -1.0);
     match g {
         "H" => {
-            if let Term::Const(Const::Ket(b)) = t {
-                let s = f64::sqrt(0.5);
-                let vec = vec![
-                    (ket(false), Complex::new(s, 0.0)),
-                    (ket(true), Complex::new(if *b { -s } else { s }, 0.0)),
-                ];
-                Ok(superpos(vec))
-            } else {
-                Err(EvalError::BadGate(format!("Hadamard failure: {}", t)))
-            }
-        }
-        "C" => {
-            if let Some((a, b)) = t.as_pair() {
-                let Term::Const(Const::Ket(b1)) = a else {
-                    return Err(EvalError::BadGate("CNOT must take a pair of qubits".into()));
-                };
-                let Term::Const(Const::Ket(b2)) = b else {
-                    return Err(EvalError::BadGate("CNOT must take a pair of qubits".into()));
+            let s = f64::sqrt(0.5);
+            [
+                Complex::new(s, 0.0),
+                Complex::new(if b { -s } else { s }, 0.0),
+            ]
+        }
+        "X" => [if b { one } else { zero }, if b { zero } else { one }],
+        "Y" => [if b { minus_i } else { zero }, if b { zero } else { i }],
+        // The remaining gates are diagonal, only changing the phase of |1>.
+        _ if !b => [one, zero],
+        "Z" => [zero, Complex::new(-1.0, 0.0)],
+        "S" => [zero, i],
+        "Sdg" => [zero, minus_i],
+        "T" => [zero, Complex::new(0.0, PI / 4.0).exp()],
+        "Tdg" => [zero, Complex::new(0.0, -PI / 4.0).exp()],
+        _ => unreachable!("not a single-qubit gate: {g}"),
+    }
+}
+
+// Gets the image of a basis state under a multi-qubit gate. All of these gates permute the basis
+// states up to a phase.
+fn multi_qubit_gate(g: &str, bits: &[bool]) -> (Vec<bool>, Complex<f64>) {
+    let one = Complex::new(1.0, 0.0);
+    match (g, bits) {
+        ("C", &[a, b]) => (vec![a, a ^ b], one),
+        ("CZ", &[a, b]) => {
+            let phase = if a && b { Complex::new(-1.0, 0.0) } else { one };
+            (vec![a, b], phase)
+        }
+        ("SWAP", &[a, b]) => (vec![b, a], one),
+        ("CCX", &[a, b, c]) => (vec![a, b, c ^ (a && b)], one),
+        _ => unreachable!("not a {}-qubit gate: {g}", bits.len()),
+    }
+}
+
+// Reads the basis states out of a church-encoded n-tuple of kets.
+fn kets(t: &Term, n: usize) -> Option<Vec<bool>> {
+    t.as_tuple(n)?
+        .into_iter()
+        .map(|k| match k {
+            Term::Const(Const::Ket(b)) => Some(*b),
+            _ => None,
+        })
+        .collect()
+}
+
+// Gets the number of qubits a gate acts on, or None if the constant is not a known gate.
+fn gate_arity(gates: &HashMap<String, Unitary>, g: &Const) -> Option<usize> {
+    match g {
+        Const::Gate(name, _) => match gates.get(name) {
+            Some(u) => Some(u.qubits()),
+            None => builtin_arity(name),
+        },
+        Const::Rotation(_, _, _) => Some(1),
+        _ => None,
+    }
+}
+
+// Gets the image of a basis state under a known gate, as the nonzero amplitudes of each basis
+// state.
+fn gate_image(
+    gates: &HashMap<String, Unitary>,
+    g: &Const,
+    bits: &[bool],
+) -> Vec<(Vec<bool>, Complex<f64>)> {
+    let column = match g {
+        Const::Gate(name, _) => match gates.get(name) {
+            Some(u) => return u.apply(bits),
+            None if bits.len() > 1 => return vec![multi_qubit_gate(name, bits)],
+            None => single_qubit_gate(name, bits[0]),
+        },
+        Const::Rotation(r, theta, _) => r.column(*theta, bits[0]),
+        _ => unreachable!("not a gate: {g}"),
+    };
+    [(vec![false], column[0]), (vec![true], column[1])]
+        .into_iter()
+        .filter(|(_, amp)| amp.norm_sqr() > 0.0)
+        .collect()
+}
+
+// Builds the argument of an n-qubit gate: the qubit itself when n is 1, and a church-encoded
+// n-tuple otherwise.
+fn gate_arg(qubits: Vec<Term>) -> Term {
+    if qubits.len() == 1 {
+        qubits.into_iter().next().unwrap()
+    } else {
+        tuple(qubits)
+    }
+}
+
+// The error raised when a gate is applied to the wrong number of qubits.
+fn arity_error(g: &Const, n: usize, t: &Term) -> EvalError {
+    let msg = if n == 1 {
+        format!("{g} gate must take 1 qubit: {t}")
+    } else {
+        format!("{g} gate must take a {n}-tuple of qubits: {t}")
+    };
+    let site = match g {
+        Const::Gate(_, site) | Const::Rotation(_, _, site) => *site,
+        _ => None,
+    };
+    EvalError::BadGate(msg, site)
+}
+
+// Applies the given quantum gate to a ket, or to a church-encoded tuple of kets for gates acting
+// on multiple qubits.
+fn apply_gate(gates: &HashMap<String, Unitary>, g: &Const, t: &Term) -> Result<Value, EvalError> {
+    let Some(n) = gate_arity(gates, g) else {
+        return Err(EvalError::BadApplication(format!("Gate not found: {}", g)));
+    };
+    let bits = match t {
+        Term::Const(Const::Ket(b)) if n == 1 => Some(vec![*b]),
+        _ if n == 1 => None,
+        _ => kets(t, n),
+    };
+    let Some(bits) = bits else {
+        return Err(arity_error(g, n, t));
+    };
+
+    let vec = gate_image(gates, g, &bits)
+        .into_iter()
+        .map(|(out, amp)| (gate_arg(out.into_iter().map(ket).collect()), amp))
+        .collect();
+    Ok(superpos(vec))
+}
+
+// Collects the register qubits referred to by a term, in order of appearance.
+fn qubits_in(t: &Term) -> Vec<usize> {
+    let mut out = Vec::new();
+    let mut stack = vec![t];
+    while let Some(t) = stack.pop() {
+        match t {
+            Term::Const(Const::Qubit(q)) => out.push(*q),
+            Term::Const(_) | Term::Var(_) => (),
+            Term::Abs(_, body) | Term::NonlinearAbs(_, body) | Term::Nonlinear(body) => {
+                stack.push(body)
+            }
+            Term::App(t1, t2) => stack.extend([&**t2, &**t1]),
+        }
+    }
+    out
+}
+
+// Replaces each register qubit in a term with a ket holding the value given for it.
+fn fill<F: Fn(usize) -> bool>(t: Term, value: &F) -> Term {
+    t.map_consts(|c| match c {
+        Const::Qubit(q) => ket(value(q)),
+        c => Term::Const(c),
+    })
+}
+
+// Gives the error to stop an evaluation with if it has been interrupted or has run out of time,
+// which is checked between steps as well as while a gate goes through the dense register.
+fn halt(
+    interrupt: Option<&'static AtomicBool>,
+    deadline: Option<Instant>,
+) -> Result<(), fn(Value) -> EvalError> {
+    if interrupt.is_some_and(|flag| flag.swap(false, Ordering::Relaxed)) {
+        return Err(EvalError::Interrupted);
+    }
+    if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+        return Err(EvalError::OutOfFuel);
+    }
+    Ok(())
+}
+
+// How applying M to a superposition is evaluated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Measurement {
+    // Randomly sample a single branch according to its probability.
+    Sample,
+    // Produce the full probability distribution over the branches.
+    Exact,
+}
+
+// How the quantum state of an evaluation is represented.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    // Each branch of a superposition is a separate term, which is reduced independently.
+    Sparse,
+    // Every qubit lives in a single dense state vector, and terms only refer to their qubits, so
+    // gates become matrix operations and evaluation never branches. The state is only expanded
+    // into a superposition of terms once evaluation finishes. It is only used when asked for,
+    // since M then measures just the qubits it is given and can't give exact distributions.
+    Dense,
+}
+
+// Which evaluator reduces terms.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Engine {
+    // Beta reduction substitutes the argument into the body of the abstraction.
+    Substitution,
+    // An environment machine binds arguments to variables in closures instead, so a reduction
+    // never copies the body it enters.
+    Machine,
+}
+
+// Which redex is reduced next, and how far.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strategy {
+    // Arguments are reduced before being passed to an abstraction, and reduction stops at
+    // abstractions.
+    ByValue,
+    // Arguments are passed to an abstraction unreduced, and reduction stops at abstractions.
+    ByName,
+    // Arguments are passed unreduced, and reduction continues under abstractions until no redex
+    // is left outside a !. Applications of variables are then left in place.
+    NormalOrder,
+}
+
+// Evaluates terms, optionally recording each reduction step taken along the way.
+pub struct Evaluator {
+    trace: Option<Vec<Step>>,
+    pub(crate) depth: usize,
+    rng: StdRng,
+    measurement: Measurement,
+    gates: HashMap<String, Unitary>,
+    backend: Backend,
+    register: StateVector,
+    engine: Engine,
+    strategy: Strategy,
+    binders: usize,
+    type_checking: bool,
+    step_limit: Option<usize>,
+    time_limit: Option<Duration>,
+    interrupt: Option<&'static AtomicBool>,
+    steps: usize,
+    deadline: Option<Instant>,
+}
+
+impl Default for Evaluator {
+    fn default() -> Self {
+        Self::new(StdRng::from_entropy())
+    }
+}
+
+impl Evaluator {
+    // Creates an evaluator which draws measurement outcomes from the given generator.
+    pub fn new(rng: StdRng) -> Self {
+        Self {
+            trace: None,
+            depth: 0,
+            rng,
+            measurement: Measurement::Sample,
+            gates: HashMap::new(),
+            backend: Backend::Sparse,
+            register: StateVector::new(),
+            engine: Engine::Substitution,
+            strategy: Strategy::ByValue,
+            binders: 0,
+            type_checking: true,
+            step_limit: None,
+            time_limit: None,
+            interrupt: None,
+            steps: 0,
+            deadline: None,
+        }
+    }
+
+    // Creates an evaluator whose measurement outcomes are determined by the seed.
+    pub fn seeded(seed: u64) -> Self {
+        Self::new(StdRng::seed_from_u64(seed))
+    }
+
+    // Resets the measurement generator to a known seed, making later evaluations reproducible.
+    pub fn reseed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    // Adds a user-defined gate, replacing any previous gate of the same name.
+    pub fn define_gate(&mut self, name: &str, u: Unitary) {
+        self.gates.insert(name.to_string(), u);
+    }
+
+    // Sets how the quantum state is represented during evaluation.
+    pub fn set_backend(&mut self, backend: Backend) {
+        self.backend = backend;
+    }
+
+    // Sets which evaluator reduces terms.
+    pub fn set_engine(&mut self, engine: Engine) {
+        self.engine = engine;
+    }
+
+    // Sets the order in which redexes are reduced.
+    pub fn set_strategy(&mut self, strategy: Strategy) {
+        self.strategy = strategy;
+    }
+
+    // Determines if abstractions are applied to their arguments before those are reduced.
+    pub(crate) fn is_lazy(&self) -> bool {
+        self.strategy != Strategy::ByValue
+    }
+
+    // Determines if an application of two reduced terms can't be reduced because its function is
+    // a variable, or a gate or measurement is applied to a term containing one. These are only
+    // left in place when reducing under an abstraction, where its variable has no value yet.
+    pub(crate) fn is_stuck(&self, t1: &Term, t2: &Term) -> bool {
+        self.binders > 0
+            && match t1 {
+                Term::Var(_) | Term::App(_, _) => true,
+                Term::Const(c) if is_primitive(c) => !t2.free_vars().is_empty(),
+                _ => false,
+            }
+    }
+
+    // Fully reduces the argument of a gate or measurement under a lazy strategy, since the
+    // qubits in a tuple may not have been reduced yet.
+    pub(crate) fn force(&mut self, v: Value) -> Result<Value, EvalError> {
+        if self.is_lazy() {
+            self.normalize(v)
+        } else {
+            Ok(v)
+        }
+    }
+
+    // Sets whether terms are type-checked before they are evaluated.
+    pub fn set_type_checking(&mut self, on: bool) {
+        self.type_checking = on;
+    }
+
+    // Infers the type of a term, checking that it uses each linear variable exactly once.
+    pub fn type_of(&self, t: &Term) -> Result<Type, TypeError> {
+        well_formed(t).map_err(|(e, at)| TypeError::LinearityViolation(e, at))?;
+        infer(t, |g| gate_arity(&self.gates, g))
+    }
+
+    // Limits how many reductions each evaluation may take, or lifts the limit.
+    pub fn set_step_limit(&mut self, steps: Option<usize>) {
+        self.step_limit = steps;
+    }
+
+    // Limits how long each evaluation may run for, or lifts the limit.
+    pub fn set_time_limit(&mut self, time: Option<Duration>) {
+        self.time_limit = time;
+    }
+
+    // Gets the limits on the number of reductions and running time of each evaluation.
+    pub fn limits(&self) -> (Option<usize>, Option<Duration>) {
+        (self.step_limit, self.time_limit)
+    }
+
+    // Makes evaluations stop as soon as the flag is raised, lowering it again.
+    pub fn set_interrupt(&mut self, flag: &'static AtomicBool) {
+        self.interrupt = Some(flag);
+    }
+
+    // Counts a reduction step, giving the error to stop with if the evaluation has run out of
+    // steps or time, or has been interrupted.
+    pub(crate) fn tick(&mut self) -> Result<(), fn(Value) -> EvalError> {
+        self.steps += 1;
+        halt(self.interrupt, self.deadline)?;
+        if self.step_limit.is_some_and(|limit| self.steps > limit) {
+            return Err(EvalError::OutOfFuel);
+        }
+        Ok(())
+    }
+
+    // Sets whether measurements are sampled or computed as exact distributions.
+    pub fn set_measurement(&mut self, measurement: Measurement) {
+        self.measurement = measurement;
+    }
+
+    // Turns recording of reduction steps on or off, discarding any previously recorded steps.
+    pub fn set_tracing(&mut self, on: bool) {
+        self.trace = if on { Some(Vec::new()) } else { None };
+    }
+
+    // Takes the steps recorded so far, leaving the trace empty.
+    pub fn take_trace(&mut self) -> Vec<Step> {
+        self.trace.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    // Determines if this evaluator is recording reduction steps.
+    pub(crate) fn is_tracing(&self) -> bool {
+        self.trace.is_some()
+    }
+
+    // Records a step if this evaluator is tracing.
+    pub(crate) fn record(&mut self, kind: StepKind) {
+        if let Some(trace) = &mut self.trace {
+            trace.push(Step {
+                depth: self.depth,
+                kind,
+            });
+        }
+    }
+
+    // Applies a term to each branch of a superposition, one level deeper in the trace.
+    pub(crate) fn split<F>(&mut self, s: &Superpos, mut f: F) -> Result<Value, EvalError>
+    where
+        F: FnMut(&mut Self, Term) -> Result<Value, EvalError>,
+    {
+        self.record(StepKind::Split {
+            branches: s.0.len(),
+        });
+        self.depth += 1;
+        let res = s.map_terms(|t| f(self, t));
+        self.depth -= 1;
+        res
+    }
+
+    // Applies a value to each component of a classical mixture.
+    pub(crate) fn distribute<F>(&mut self, m: &Mixture, mut f: F) -> Result<Value, EvalError>
+    where
+        F: FnMut(&mut Self, Value) -> Result<Value, EvalError>,
+    {
+        let mut res = m.map_values(|v| f(self, v))?;
+        res.merge();
+        Ok(Value::Mixed(res))
+    }
+
+    pub(crate) fn apply(&mut self, v1: Value, v2: Value) -> Result<Value, EvalError> {
+        match (v1, v2) {
+            (Value::Mixed(m), v2) => self.distribute(&m, |ev, v1| ev.apply(v1, v2.clone())),
+            (v1, Value::Mixed(m)) => self.distribute(&m, |ev, v2| ev.apply(v1.clone(), v2)),
+            (Value::Term(t1), Value::Term(t2)) if self.is_stuck(&t1, &t2) => {
+                Ok(Value::Term(app(t1, t2)))
+            }
+            (
+                Value::Term(Term::Const(ref g @ (Const::Gate(_, _) | Const::Rotation(_, _, _)))),
+                Value::Term(t),
+            ) => {
+                let g = g.clone();
+                let res = match self.backend {
+                    Backend::Sparse => apply_gate(&self.gates, &g, &t)?,
+                    Backend::Dense => self.apply_gate_dense(&g, &t)?,
                 };
-                let out = match (b1, b2) {
-                    (false, false) => pair(ket(false), ket(false)),
-                    (false, true) => pair(ket(false), ket(true)),
-                    (true, false) => pair(ket(true), ket(true)),
-                    (true, true) => pair(ket(true), ket(false)),
+                if self.trace.is_some() {
+                    self.record(StepKind::Gate {
+                        gate: Term::Const(g),
+                        arg: t,
+                        result: res.clone(),
+                    });
+                }
+                Ok(res)
+            }
+            (Value::Term(Term::Const(Const::Meas)), Value::Term(t))
+                if self.backend == Backend::Dense =>
+            {
+                self.measure_dense(t)
+            }
+            (Value::Term(Term::Const(Const::Meas)), Value::Superpos(s)) => {
+                let state = self.trace.as_ref().map(|_| s.clone());
+                let res = match self.measurement {
+                    Measurement::Sample => Value::Term(s.measure(&mut self.rng)),
+                    Measurement::Exact => Value::Mixed(s.distribution()),
                 };
-                Ok(superpos(vec![(out, Complex::new(1.0, 0.0))]))
-            } else {
-                Err(EvalError::BadGate("CNOT must take a pair of qubits".into()))
+                if let Some(state) = state {
+                    self.record(StepKind::Measure {
+                        state,
+                        result: res.clone(),
+                    });
+                }
+                Ok(res)
             }
-        }
-        "T" => {
-            if let Term::Const(Const::Ket(b)) = t {
-                let phase = Complex::new(0.0, PI / 4.0).exp();
-                let vec = vec![(ket(*b), if *b { phase } else { Complex::new(1.0, 0.0) })];
-                Ok(superpos(vec))
-            } else {
-                Err(EvalError::BadGate("T gate must take 1 qubit".into()))
+            (Value::Term(t1), Value::Term(t2)) => {
+                let traced = self.trace.as_ref().map(|_| (t1.clone(), t2.clone()));
+                let res = beta_reduce(t1, t2)?;
+                if let Some((abs, arg)) = traced {
+                    self.record(StepKind::Beta {
+                        abs,
+                        arg,
+                        result: res.clone(),
+                    });
+                }
+                Ok(Value::Term(res))
+            }
+            (Value::Term(t), Value::Superpos(s)) => self.split(&s, |ev, t2| {
+                ev.apply(Value::Term(t.clone()), Value::Term(t2))
+            }),
+            (Value::Superpos(s), Value::Term(t)) => self.split(&s, |ev, t2| {
+                ev.apply(Value::Term(t2), Value::Term(t.clone()))
+            }),
+            (Value::Superpos(s1), Value::Superpos(s2)) => {
+                self.record(StepKind::Split {
+                    branches: s1.0.len() * s2.0.len(),
+                });
+                self.depth += 1;
+                let res = s1.zip_terms(&s2, |t1, t2| self.apply(Value::Term(t1), Value::Term(t2)));
+                self.depth -= 1;
+                res
             }
         }
-        _ => Err(EvalError::BadApplication(format!("Gate not found: {}", g))),
     }
-}
 
-fn apply(v1: Value, v2: Value) -> Result<Value, EvalError> {
-    match (v1, v2) {
-        (Value::Term(Term::Const(Const::Gate(g))), Value::Term(t)) => apply_gate(&g, &t),
-        (Value::Term(Term::Const(Const::Meas)), Value::Superpos(s)) => Ok(Value::Term(s.measure())),
-        (Value::Term(t1), Value::Term(t2)) => Ok(Value::Term(beta_reduce(t1, t2)?)),
-        (Value::Term(t), Value::Superpos(s)) => {
-            Ok(Value::Superpos(s.map_terms(|t2| {
-                apply(Value::Term(t.clone()), Value::Term(t2))
-            })?))
+    // Applies a gate to qubits in the register, first moving any kets it is given into the
+    // register.
+    fn apply_gate_dense(&mut self, g: &Const, t: &Term) -> Result<Value, EvalError> {
+        let Some(n) = gate_arity(&self.gates, g) else {
+            return Err(EvalError::BadApplication(format!("Gate not found: {}", g)));
+        };
+        let args = if n == 1 { Some(vec![t]) } else { t.as_tuple(n) };
+        let Some(args) = args.filter(|args| {
+            args.iter()
+                .all(|a| matches!(a, Term::Const(Const::Ket(_) | Const::Qubit(_))))
+        }) else {
+            return Err(arity_error(g, n, t));
+        };
+
+        let qs: Vec<usize> = args
+            .into_iter()
+            .map(|a| match a {
+                Term::Const(Const::Ket(b)) => self.register.alloc(*b),
+                Term::Const(Const::Qubit(q)) => *q,
+                _ => unreachable!(),
+            })
+            .collect();
+        self.check_qubits(&qs)?;
+        if let Some(q) = (0..qs.len()).find_map(|i| qs[..i].contains(&qs[i]).then_some(qs[i])) {
+            return Err(EvalError::BadApplication(format!(
+                "Qubit |q{q}> is given to {g} more than once"
+            )));
         }
-        (Value::Superpos(s), Value::Term(t)) => {
-            Ok(Value::Superpos(s.map_terms(|t2| {
-                apply(Value::Term(t2), Value::Term(t.clone()))
-            })?))
+        let out = gate_arg(qs.iter().map(|q| Term::Const(Const::Qubit(*q))).collect());
+        let (gates, interrupt, deadline) = (&self.gates, self.interrupt, self.deadline);
+        let applied = self.register.apply(
+            &qs,
+            |bits| gate_image(gates, g, bits),
+            || halt(interrupt, deadline),
+        );
+        match applied {
+            Ok(()) => Ok(Value::Term(out)),
+            Err(stop) => Err(stop(Value::Term(app(Term::Const(g.clone()), out)))),
         }
-        (Value::Superpos(s1), Value::Superpos(s2)) => {
-            Ok(Value::Superpos(s1.zip_terms(&s2, |t1, t2| {
-                apply(Value::Term(t1), Value::Term(t2))
-            })?))
+    }
+
+    // Measures every register qubit in a term, replacing each with the ket it collapsed to.
+    fn measure_dense(&mut self, t: Term) -> Result<Value, EvalError> {
+        if self.measurement == Measurement::Exact {
+            return Err(EvalError::BadApplication(
+                "Exact measurement is not supported by the dense backend".to_string(),
+            ));
+        }
+
+        let qs = qubits_in(&t);
+        self.check_qubits(&qs)?;
+        // Expanding the register into terms takes a branch for each of its basis states, so it
+        // is only done to record the state being measured
+        let state = match self.trace {
+            Some(_) => match self.expand(t.clone()) {
+                Value::Superpos(s) => Some(s),
+                Value::Term(t) => Some(Superpos::trivial(t)),
+                Value::Mixed(_) => unreachable!("a term expands to a pure state"),
+            },
+            None => None,
+        };
+        let outcome = self.register.measure(&qs, &mut self.rng);
+        let res = fill(t, &|q| outcome[qs.iter().position(|q2| *q2 == q).unwrap()]);
+        if let Some(state) = state {
+            self.record(StepKind::Measure {
+                state,
+                result: Value::Term(res.clone()),
+            });
         }
+        Ok(Value::Term(res))
     }
-}
 
-pub fn eval(term: Term) -> Result<Value, EvalError> {
-    // We do basic term-checking before evaluation to catch out linearity errors
-    if let Err(e) = well_formed(&term) { return Err(EvalError::LinearityViolation(e)) }
-
-    fn helper(term: Term) -> Result<Value, EvalError> {
-        match term {
-            Term::Const(_)
-            | Term::Abs(_, _)
-            | Term::NonlinearAbs(_, _)
-            | Term::Nonlinear(_)
-            | Term::Var(_) => Ok(Value::Term(term)),
-            Term::App(t1, t2) => {
-                let v1 = helper(*t1)?;
-                let v2 = helper(*t2)?;
-                let res = apply(v1, v2)?;
-                match res {
-                    Value::Term(t) => helper(t),
+    // Checks that qubits written as |qN> refer to qubits already in the register.
+    fn check_qubits(&self, qs: &[usize]) -> Result<(), EvalError> {
+        match qs.iter().find(|q| **q >= self.register.qubits()) {
+            Some(q) => Err(EvalError::BadApplication(format!(
+                "Qubit |q{q}> is not in the register, which has {} qubits",
+                self.register.qubits()
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    // Converts a term referring to register qubits back into a superposition of terms, with one
+    // branch for each basis state of the register. Qubits only leave a term by being measured, so
+    // no two basis states give the same branch and there is nothing to merge. The qubits must
+    // already have been checked to be in the register.
+    fn expand(&self, t: Term) -> Value {
+        if qubits_in(&t).is_empty() {
+            return Value::Term(t);
+        }
+
+        let vec = self
+            .register
+            .branches()
+            .map(|(i, amp)| (fill(t.clone(), &|q| bit(i, q)), amp))
+            .collect();
+        Value::Superpos(Superpos(vec))
+    }
+
+    pub fn eval(&mut self, term: Term) -> Result<Value, EvalError> {
+        self.steps = 0;
+        self.binders = 0;
+        self.deadline = self.time_limit.map(|limit| Instant::now() + limit);
+        if let Some(flag) = self.interrupt {
+            flag.store(false, Ordering::Relaxed);
+        }
+        if self.type_checking {
+            self.type_of(&term).map_err(EvalError::TypeError)?;
+        } else if let Err((e, at)) = well_formed(&term) {
+            return Err(EvalError::LinearityViolation(e, Some(at)));
+        }
+
+        match self.backend {
+            Backend::Sparse => self.eval_normal(term),
+            Backend::Dense => {
+                self.register = StateVector::new();
+                match self.eval_normal(term) {
+                    Ok(Value::Term(t)) => {
+                        self.check_qubits(&qubits_in(&t))?;
+                        Ok(self.expand(t))
+                    }
+                    Ok(v) => Ok(v),
+                    // A term the evaluation stopped at is left as it is if it refers to qubits
+                    // which aren't in the register, since there is already an error to report
+                    Err(e) => Err(e.within(|v| match v {
+                        Value::Term(t) if self.check_qubits(&qubits_in(&t)).is_ok() => {
+                            self.expand(t)
+                        }
+                        v => v,
+                    })),
+                }
+            }
+        }
+    }
+
+    // Evaluates a term as far as the strategy reduces it.
+    fn eval_normal(&mut self, term: Term) -> Result<Value, EvalError> {
+        let v = self.eval_branch(term)?;
+        match self.strategy {
+            Strategy::NormalOrder => self.normalize(v),
+            Strategy::ByValue | Strategy::ByName => Ok(v),
+        }
+    }
+
+    // Continues normal-order reduction of a value under its abstractions and into the arguments
+    // of its stuck applications. A superposition reached under an abstraction is lifted out of
+    // it, giving a superposition of abstractions. The abstractions and applications around the
+    // subterm being reduced are kept on an explicit stack, so that deep terms can't overflow the
+    // call stack.
+    fn normalize(&mut self, v: Value) -> Result<Value, EvalError> {
+        enum Frame {
+            // Rebuild an abstraction around its reduced body.
+            Abs(String),
+            NonlinearAbs(String),
+            // Normalize the argument of an application whose function is being normalized.
+            Arg(Term),
+            // Apply the normalized function of an application to its normalized argument.
+            Apply(Value),
+        }
+
+        // Rebuilds the term reached so far by placing a value back into the frames around it.
+        fn plug(mut v: Value, stack: Vec<Frame>) -> Value {
+            for frame in stack.into_iter().rev() {
+                v = match frame {
+                    Frame::Abs(x) => map_value(v, &|t| abs(&x, t)),
+                    Frame::NonlinearAbs(x) => map_value(v, &|t| nonlinear_abs(&x, t)),
+                    Frame::Arg(t2) => app_values(v, Value::Term(t2)),
+                    Frame::Apply(v1) => app_values(v1, v),
+                };
+            }
+            v
+        }
+
+        let binders = self.binders;
+        let mut stack = Vec::new();
+        let mut next = v;
+        loop {
+            // Descend into the body of each abstraction and the function of each application,
+            // reducing each body as it is reached.
+            let res = loop {
+                let t = match next {
+                    Value::Term(t) => t,
                     Value::Superpos(s) => {
-                        let mut s_new = s.map_terms(eval)?;
-                        s_new.merge();
-                        Ok(Value::Superpos(s_new))
+                        break s
+                            .map_terms(|t| self.normalize(Value::Term(t)))
+                            .map(|mut res| {
+                                res.merge();
+                                res
+                            });
+                    }
+                    Value::Mixed(m) => break self.distribute(&m, |ev, v| ev.normalize(v)),
+                };
+                let body = match t.into_node() {
+                    Node::Abs(x, body) => {
+                        stack.push(Frame::Abs(x));
+                        body
+                    }
+                    Node::NonlinearAbs(x, body) => {
+                        stack.push(Frame::NonlinearAbs(x));
+                        body
+                    }
+                    Node::App(t1, t2) => {
+                        stack.push(Frame::Arg(t2));
+                        next = Value::Term(t1);
+                        continue;
+                    }
+                    node => break Ok(Value::Term(node.into_term())),
+                };
+                self.binders += 1;
+                match self.eval_unchecked(body) {
+                    Ok(v) => next = v,
+                    Err(e) => break Err(e),
+                }
+            };
+            let mut v = match res {
+                Ok(v) => v,
+                Err(e) => {
+                    self.binders = binders;
+                    return Err(e.within(|v| plug(v, stack)));
+                }
+            };
+
+            // Return the value to the frames waiting on it until one has a term to normalize.
+            next = loop {
+                match stack.pop() {
+                    None => return Ok(v),
+                    Some(Frame::Abs(x)) => {
+                        self.binders -= 1;
+                        v = map_value(v, &|t| abs(&x, t));
+                    }
+                    Some(Frame::NonlinearAbs(x)) => {
+                        self.binders -= 1;
+                        v = map_value(v, &|t| nonlinear_abs(&x, t));
+                    }
+                    Some(Frame::Arg(t2)) => {
+                        stack.push(Frame::Apply(v));
+                        break Value::Term(t2);
+                    }
+                    Some(Frame::Apply(v1)) => v = app_values(v1, v),
+                }
+            };
+        }
+    }
+
+    // Evaluates a term without resetting the register, as for each branch of a superposition.
+    fn eval_branch(&mut self, term: Term) -> Result<Value, EvalError> {
+        // We do basic term-checking before evaluation to catch out linearity errors
+        if let Err((e, _)) = well_formed(&term) {
+            return Err(EvalError::LinearityViolation(e, None));
+        }
+        self.eval_unchecked(term)
+    }
+
+    // Evaluates a term already known to be well formed, as the body of an abstraction reached
+    // by evaluating a well-formed term, without checking it again.
+    fn eval_unchecked(&mut self, term: Term) -> Result<Value, EvalError> {
+        match self.engine {
+            Engine::Substitution => self.helper(term),
+            Engine::Machine => self.run_machine(&term),
+        }
+    }
+
+    // Reduces a term, keeping the applications still waiting on a value on an explicit stack
+    // rather than the call stack, so that long chains of reductions can't overflow it.
+    fn helper(&mut self, term: Term) -> Result<Value, EvalError> {
+        enum Frame {
+            // Evaluate the argument of an application whose function is being evaluated.
+            Arg(Term),
+            // Apply the function of an application whose argument is being evaluated.
+            Apply(Value),
+        }
+
+        // Rebuilds the term reached so far by placing a value back into the applications
+        // waiting on it.
+        fn plug(mut v: Value, stack: Vec<Frame>) -> Value {
+            for frame in stack.into_iter().rev() {
+                v = match frame {
+                    Frame::Arg(t2) => app_values(v, Value::Term(t2)),
+                    Frame::Apply(v1) => app_values(v1, v),
+                };
+            }
+            v
+        }
+
+        let mut stack = Vec::new();
+        let mut term = term;
+        loop {
+            // Descend the left spine of applications to the function at its head.
+            while let Term::App(..) = term {
+                let Node::App(t1, t2) = term.into_node() else {
+                    unreachable!()
+                };
+                stack.push(Frame::Arg(t2));
+                term = t1;
+            }
+            let mut v = Value::Term(term);
+
+            // Return the value to the frames waiting on it until one has a term to evaluate.
+            term = loop {
+                match stack.pop() {
+                    None => return Ok(v),
+                    Some(Frame::Arg(t2)) => {
+                        let lazy = self.is_lazy() && matches!(v, Value::Term(Term::Abs(_, _)));
+                        stack.push(Frame::Apply(v));
+                        if lazy {
+                            // The abstraction is applied to the argument as it is.
+                            v = Value::Term(t2);
+                            continue;
+                        }
+                        break t2;
+                    }
+                    Some(Frame::Apply(v1)) => {
+                        if let Err(stop) = self.tick() {
+                            return Err(stop(plug(app_values(v1, v), stack)));
+                        }
+                        if let (Value::Term(t1), Value::Term(t2)) = (&v1, &v)
+                            && self.is_stuck(t1, t2)
+                        {
+                            // A stuck application is already reduced, so it is not evaluated again.
+                            v = app_values(v1, v);
+                            continue;
+                        }
+                        if matches!(&v1, Value::Term(Term::Const(c)) if is_primitive(c)) {
+                            v = match self.force(v) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    return Err(e.within(|v| plug(app_values(v1, v), stack)));
+                                }
+                            };
+                        }
+                        let res = self.apply(v1, v).and_then(|res| match res {
+                            Value::Term(t) => Ok(Value::Term(t)),
+                            res => self.reduce(res),
+                        });
+                        match res {
+                            Ok(Value::Term(t)) => break t,
+                            Ok(res) => v = res,
+                            Err(e) => return Err(e.within(|v| plug(v, stack))),
+                        }
                     }
                 }
+            };
+        }
+    }
+
+    // Continues evaluating the result of an application until it reaches a normal form.
+    fn reduce(&mut self, v: Value) -> Result<Value, EvalError> {
+        match v {
+            Value::Term(t) => self.helper(t),
+            Value::Superpos(s) => {
+                let mut s_new = self.split(&s, |ev, t| ev.eval_branch(t))?;
+                s_new.merge();
+                Ok(s_new)
             }
+            Value::Mixed(m) => self.distribute(&m, |ev, v| ev.reduce(v)),
+        }
+    }
+}
+
+pub fn eval(term: Term) -> Result<Value, EvalError> {
+    Evaluator::default().eval(term)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Backend, Engine, EvalError, Evaluator, Term, Value};
+    use crate::parser::parse;
+
+    fn term(source: &str) -> Term {
+        parse(&mut source.chars()).unwrap()
+    }
+
+    #[test]
+    fn dense_measurement_of_a_large_register_is_fast() {
+        let qubits = 20;
+        let register = vec!["H |0>"; qubits].join(", ");
+        let mut ev = Evaluator::seeded(0);
+        ev.set_backend(Backend::Dense);
+        let start = Instant::now();
+        let v = ev.eval(term(&format!("M ({register})"))).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(10));
+        let Value::Term(t) = v else {
+            panic!("measuring the whole register leaves a single branch: {v}");
+        };
+        assert_eq!(t.as_basis().map(|bits| bits.len()), Some(qubits));
+    }
+
+    #[test]
+    fn dense_measurement_of_a_deep_term() {
+        let depth = 30_000;
+        let source = format!(
+            "M (H |0>, {}|1>{})",
+            "(|0>, ".repeat(depth),
+            ")".repeat(depth)
+        );
+        let mut ev = Evaluator::seeded(0);
+        ev.set_backend(Backend::Dense);
+        ev.set_type_checking(false);
+        assert!(ev.eval(term(&source)).is_ok());
+    }
+
+    #[test]
+    fn dense_qubits_must_be_in_the_register() {
+        let mut ev = Evaluator::seeded(0);
+        ev.set_backend(Backend::Dense);
+        for source in ["|q0>", "H |q5>", "M |q3>", "(H |0>, |q7>)"] {
+            assert!(ev.eval(term(source)).is_err(), "{source}");
+        }
+    }
+
+    #[test]
+    fn deep_terms_evaluate_on_both_engines() {
+        let depth = 100_000;
+        let source = format!("\\x. {}x{}", "(\\y. y) (".repeat(depth), ")".repeat(depth));
+        for engine in [Engine::Substitution, Engine::Machine] {
+            let mut ev = Evaluator::seeded(0);
+            ev.set_engine(engine);
+            ev.set_type_checking(false);
+            let t = term(&source);
+            let v = ev.eval(t.clone()).unwrap();
+            assert!(v.alpha_eq(&Value::Term(t)), "{engine:?}");
+        }
+    }
+
+    #[test]
+    fn long_reductions_stop_on_both_engines() {
+        for engine in [Engine::Substitution, Engine::Machine] {
+            let mut ev = Evaluator::seeded(0);
+            ev.set_engine(engine);
+            ev.set_type_checking(false);
+            ev.set_step_limit(Some(100_000));
+            let res = ev.eval(term("(#x. x !(x)) !(#x. x !(x))"));
+            assert!(matches!(res, Err(EvalError::OutOfFuel(_))), "{engine:?}");
+        }
+    }
+
+    #[test]
+    fn renamed_binders_print_back() {
+        let mut ev = Evaluator::seeded(0);
+        ev.set_type_checking(false);
+        for source in [
+            "(#x. \\y. (x, y)) !(y)",
+            "(#x. \\`my var`. (x, `my var`)) !(`my var`)",
+            "(#x. \\b. \\b1. b x b1) !(b)",
+        ] {
+            let Ok(Value::Term(t)) = ev.eval(term(source)) else {
+                panic!("{source} didn't evaluate to a term");
+            };
+            assert_eq!(term(&t.to_string()), t, "{source} gave {t}");
         }
     }
-    helper(term)
 }
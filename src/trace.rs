@@ -0,0 +1,77 @@
+use crate::{
+    helpers::app,
+    pretty,
+    superpos::Superpos,
+    term::{Term, Value},
+};
+use std::fmt::Display;
+
+// ANSI escapes used to highlight the redex of a step when printing a trace.
+const HIGHLIGHT: &str = "\x1b[1;4m";
+const RESET: &str = "\x1b[0m";
+
+// The kinds of reduction that can be recorded during a traced evaluation.
+#[derive(Clone, Debug)]
+pub enum StepKind {
+    // A classical beta reduction of an abstraction applied to an argument.
+    Beta {
+        abs: Term,
+        arg: Term,
+        result: Term,
+    },
+    // A quantum gate applied to a ket or a tuple of kets.
+    Gate {
+        gate: Term,
+        arg: Term,
+        result: Value,
+    },
+    // A measurement collapsing a superposition down to a single branch, or to the distribution
+    // over its branches when measuring exactly.
+    Measure {
+        state: Superpos,
+        result: Value,
+    },
+    // An application lifted over each branch of a superposition.
+    Split {
+        branches: usize,
+    },
+}
+
+// A single step of a traced evaluation, along with how many superposition branches deep it
+// occurred.
+#[derive(Clone, Debug)]
+pub struct Step {
+    pub depth: usize,
+    pub kind: StepKind,
+}
+
+// Formats a value on a single line, since superpositions and mixtures may otherwise span several.
+fn inline(v: &Value) -> String {
+    pretty::value(v, usize::MAX)
+}
+
+impl Display for Step {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", "  ".repeat(self.depth))?;
+        match &self.kind {
+            StepKind::Beta { abs, arg, result } => {
+                let redex = app(abs.clone(), arg.clone());
+                write!(f, "beta:    {HIGHLIGHT}{redex}{RESET} ~> {result}")
+            }
+            StepKind::Gate { gate, arg, result } => {
+                let redex = app(gate.clone(), arg.clone());
+                let result = inline(result);
+                write!(f, "gate:    {HIGHLIGHT}{redex}{RESET} ~> {result}")
+            }
+            StepKind::Measure { state, result } => {
+                write!(
+                    f,
+                    "measure: {HIGHLIGHT}M{RESET} over {} branches ~> {}",
+                    state.0.len(),
+                    inline(result)
+                )
+            }
+            StepKind::Split { branches } => write!(f, "split:   into {branches} branches"),
+        }
+    }
+}
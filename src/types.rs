@@ -0,0 +1,445 @@
+use crate::term::{Const, Term};
+use std::collections::HashMap;
+
+// A type of the linear lambda calculus. Types still being inferred contain numbered variables.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Type {
+    Qubit,
+    Var(usize),
+    // A function which uses its argument exactly once, A ⊸ B.
+    Lolli(Box<Type>, Box<Type>),
+    // A suspended term, which can be used any number of times, !A.
+    Bang(Box<Type>),
+    // A Church tuple, or the tuple of qubits a gate takes and gives, A ⊗ B. A tuple passes its
+    // components to a continuation of any result type.
+    Tensor(Vec<Type>),
+}
+
+// An error in a term, along with the position of the offending subterm in a pre-order traversal
+// of the term.
+#[derive(Debug, Clone)]
+pub enum TypeError {
+    // A function was applied to an argument of the wrong type.
+    Mismatch(String, usize),
+    // An application would need a type containing itself, as for self-application.
+    Infinite(String, usize),
+    UndefinedSymbol(String, usize),
+    LinearityViolation(String, usize),
+}
+
+impl TypeError {
+    // Gets the position of the offending subterm in a pre-order traversal of the term.
+    pub fn node(&self) -> usize {
+        match self {
+            TypeError::Mismatch(_, at)
+            | TypeError::Infinite(_, at)
+            | TypeError::UndefinedSymbol(_, at)
+            | TypeError::LinearityViolation(_, at) => *at,
+        }
+    }
+}
+
+impl std::fmt::Display for TypeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TypeError::Mismatch(e, _) | TypeError::LinearityViolation(e, _) => write!(f, "{e}"),
+            TypeError::Infinite(e, _) => write!(
+                f,
+                "{e} (self-application has no type; turn type checking off to evaluate it)"
+            ),
+            TypeError::UndefinedSymbol(x, _) => write!(f, "undefined symbol {x}"),
+        }
+    }
+}
+
+// Why two types failed to unify.
+enum Clash {
+    Mismatch,
+    Infinite,
+}
+
+// Builds the curried function type taking each of the components in turn.
+fn curry(ts: Vec<Type>, res: Type) -> Type {
+    ts.into_iter()
+        .rev()
+        .fold(res, |res, t| Type::Lolli(Box::new(t), Box::new(res)))
+}
+
+// The type variables solved so far while inferring the type of a term.
+#[derive(Default)]
+struct Solution {
+    vars: Vec<Option<Type>>,
+    // The variables solved by the unification in progress, so that it can be undone.
+    trail: Vec<usize>,
+}
+
+impl Solution {
+    fn fresh(&mut self) -> Type {
+        self.vars.push(None);
+        Type::Var(self.vars.len() - 1)
+    }
+
+    // Follows solved variables until reaching a type that is not one.
+    fn head<'a>(&'a self, mut t: &'a Type) -> &'a Type {
+        while let Type::Var(i) = t {
+            match &self.vars[*i] {
+                Some(u) => t = u,
+                None => break,
+            }
+        }
+        t
+    }
+
+    // Determines if the variable occurs in the type.
+    fn occurs(&self, i: usize, t: &Type) -> bool {
+        let mut stack = vec![t];
+        while let Some(t) = stack.pop() {
+            match self.head(t) {
+                Type::Qubit => {}
+                Type::Var(j) => {
+                    if i == *j {
+                        return true;
+                    }
+                }
+                Type::Lolli(a, b) => stack.extend([a.as_ref(), b.as_ref()]),
+                Type::Bang(a) => stack.push(a),
+                Type::Tensor(ts) => stack.extend(ts),
+            }
+        }
+        false
+    }
+
+    // Solves variables to make two types equal. If they can't be, the solution is left as it was.
+    fn unify(&mut self, a: Type, b: Type) -> Result<(), Clash> {
+        self.trail.clear();
+        let res = self.unify_all(a, b);
+        if res.is_err() {
+            for i in self.trail.drain(..) {
+                self.vars[i] = None;
+            }
+        }
+        res
+    }
+
+    fn unify_all(&mut self, a: Type, b: Type) -> Result<(), Clash> {
+        let mut work = vec![(a, b)];
+        while let Some((a, b)) = work.pop() {
+            let a = self.head(&a).clone();
+            let b = self.head(&b).clone();
+            match (a, b) {
+                (Type::Var(i), Type::Var(j)) if i == j => {}
+                (Type::Var(i), t) | (t, Type::Var(i)) => {
+                    if self.occurs(i, &t) {
+                        return Err(Clash::Infinite);
+                    }
+                    self.vars[i] = Some(t);
+                    self.trail.push(i);
+                }
+                (Type::Qubit, Type::Qubit) => {}
+                (Type::Lolli(a1, b1), Type::Lolli(a2, b2)) => {
+                    work.push((*b1, *b2));
+                    work.push((*a1, *a2));
+                }
+                (Type::Bang(a1), Type::Bang(a2)) => work.push((*a1, *a2)),
+                (Type::Tensor(ts), Type::Tensor(us)) if ts.len() == us.len() => {
+                    work.extend(ts.into_iter().zip(us));
+                }
+                // Eliminating a tuple passes its components to a continuation.
+                (Type::Tensor(ts), Type::Lolli(k, r)) | (Type::Lolli(k, r), Type::Tensor(ts)) => {
+                    work.push((*k, curry(ts, *r)));
+                }
+                _ => return Err(Clash::Mismatch),
+            }
+        }
+        Ok(())
+    }
+
+    // Replaces every solved variable in the type by its solution.
+    fn resolve(&self, t: &Type) -> Type {
+        enum Task<'a> {
+            Visit(&'a Type),
+            Lolli,
+            Bang,
+            Tensor(usize),
+        }
+
+        let mut stack = vec![Task::Visit(t)];
+        let mut out = Vec::new();
+        while let Some(task) = stack.pop() {
+            match task {
+                Task::Visit(t) => match self.head(t) {
+                    t @ (Type::Qubit | Type::Var(_)) => out.push(t.clone()),
+                    Type::Lolli(a, b) => {
+                        stack.push(Task::Lolli);
+                        stack.push(Task::Visit(b));
+                        stack.push(Task::Visit(a));
+                    }
+                    Type::Bang(a) => {
+                        stack.push(Task::Bang);
+                        stack.push(Task::Visit(a));
+                    }
+                    Type::Tensor(ts) => {
+                        stack.push(Task::Tensor(ts.len()));
+                        stack.extend(ts.iter().rev().map(Task::Visit));
+                    }
+                },
+                Task::Lolli => {
+                    let b = out.pop().expect("missing resolved type");
+                    let a = out.pop().expect("missing resolved type");
+                    out.push(Type::Lolli(Box::new(a), Box::new(b)));
+                }
+                Task::Bang => {
+                    let a = out.pop().expect("missing resolved type");
+                    out.push(Type::Bang(Box::new(a)));
+                }
+                Task::Tensor(n) => {
+                    let ts = out.split_off(out.len() - n);
+                    out.push(Type::Tensor(ts));
+                }
+            }
+        }
+        out.pop().expect("missing resolved type")
+    }
+}
+
+// Gets the type of a constant, where gates act on as many qubits as the arity function gives.
+fn const_type<F: Fn(&Const) -> Option<usize>>(
+    c: &Const,
+    arity: &F,
+    solution: &mut Solution,
+    at: usize,
+) -> Result<Type, TypeError> {
+    match c {
+        Const::Ket(_) | Const::Qubit(_) => Ok(Type::Qubit),
+        Const::Meas => {
+            let a = solution.fresh();
+            Ok(Type::Lolli(Box::new(a.clone()), Box::new(a)))
+        }
+        Const::Gate(_, _) | Const::Rotation(_, _, _) => {
+            let qubits = match arity(c) {
+                Some(1) => Type::Qubit,
+                Some(n) => Type::Tensor(vec![Type::Qubit; n]),
+                None => return Err(TypeError::UndefinedSymbol(c.to_string(), at)),
+            };
+            Ok(Type::Lolli(Box::new(qubits.clone()), Box::new(qubits)))
+        }
+    }
+}
+
+// Infers the most general type of a term by unification. Linear and nonlinear variables are
+// typed alike; that each linear variable is used exactly once is checked separately.
+pub fn infer<F: Fn(&Const) -> Option<usize>>(t: &Term, arity: F) -> Result<Type, TypeError> {
+    // The work remaining in the inference, kept on an explicit stack so that deeply nested terms
+    // can't overflow the call stack.
+    enum Task<'a> {
+        Visit(&'a Term),
+        // Leaves the scope of a binder, typing the abstraction from its variable and body.
+        Abs,
+        NonlinearAbs,
+        // Types the application at the given node from the types of its subterms.
+        App(&'a Term, &'a Term, usize),
+        Nonlinear,
+        Tensor(usize),
+    }
+
+    let mut solution = Solution::default();
+    let mut scope: Vec<(&str, Type)> = Vec::new();
+    let mut stack = vec![Task::Visit(t)];
+    let mut out: Vec<Type> = Vec::new();
+    let mut next = 0;
+    while let Some(task) = stack.pop() {
+        match task {
+            Task::Visit(t) => {
+                let at = next;
+                next += 1;
+                match t {
+                    Term::Var(x) => match scope.iter().rev().find(|(y, _)| y == x) {
+                        Some((_, a)) => out.push(a.clone()),
+                        None => return Err(TypeError::UndefinedSymbol(x.clone(), at)),
+                    },
+                    Term::Const(c) => out.push(const_type(c, &arity, &mut solution, at)?),
+                    Term::Abs(_, _) if let Some(ts) = t.as_any_tuple() => {
+                        // The components come after the applications of the tuple's variable to them.
+                        next += ts.len() + 1;
+                        stack.push(Task::Tensor(ts.len()));
+                        stack.extend(ts.into_iter().rev().map(Task::Visit));
+                    }
+                    Term::Abs(x, body) => {
+                        scope.push((x, solution.fresh()));
+                        stack.push(Task::Abs);
+                        stack.push(Task::Visit(body));
+                    }
+                    Term::NonlinearAbs(x, body) => {
+                        scope.push((x, solution.fresh()));
+                        stack.push(Task::NonlinearAbs);
+                        stack.push(Task::Visit(body));
+                    }
+                    Term::App(t1, t2) => {
+                        stack.push(Task::App(t1, t2, at));
+                        stack.push(Task::Visit(t2));
+                        stack.push(Task::Visit(t1));
+                    }
+                    Term::Nonlinear(t) => {
+                        stack.push(Task::Nonlinear);
+                        stack.push(Task::Visit(t));
+                    }
+                }
+            }
+            task @ (Task::Abs | Task::NonlinearAbs) => {
+                let body = out.pop().expect("missing subterm type");
+                let (_, a) = scope.pop().expect("unbalanced scope");
+                let a = match task {
+                    Task::NonlinearAbs => Type::Bang(Box::new(a)),
+                    _ => a,
+                };
+                out.push(Type::Lolli(Box::new(a), Box::new(body)));
+            }
+            Task::App(t1, t2, at) => {
+                let a2 = out.pop().expect("missing subterm type");
+                let a1 = out.pop().expect("missing subterm type");
+                let res = solution.fresh();
+                let f = Type::Lolli(Box::new(a2.clone()), Box::new(res.clone()));
+                match solution.unify(a1.clone(), f) {
+                    Ok(()) => out.push(res),
+                    Err(clash) => {
+                        let [s1, s2] = show_types([&solution.resolve(&a1), &solution.resolve(&a2)]);
+                        return Err(match clash {
+                            Clash::Mismatch => TypeError::Mismatch(
+                                format!("{t1} of type {s1} cannot be applied to {t2} of type {s2}"),
+                                at,
+                            ),
+                            Clash::Infinite => TypeError::Infinite(
+                                format!(
+                                    "{t1} of type {s1} cannot be applied to {t2} of type {s2} without an infinite type"
+                                ),
+                                at,
+                            ),
+                        });
+                    }
+                }
+            }
+            Task::Nonlinear => {
+                let a = out.pop().expect("missing subterm type");
+                out.push(Type::Bang(Box::new(a)));
+            }
+            Task::Tensor(n) => {
+                let ts = out.split_off(out.len() - n);
+                out.push(Type::Tensor(ts));
+            }
+        }
+    }
+    Ok(solution.resolve(&out.pop().expect("missing term type")))
+}
+
+// Gets the name of the nth type variable to be printed: a, b, ..., z, a1, b1, ...
+fn var_name(n: usize) -> String {
+    let c = (b'a' + (n % 26) as u8) as char;
+    match n / 26 {
+        0 => c.to_string(),
+        k => format!("{c}{k}"),
+    }
+}
+
+impl std::fmt::Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write_type(self, &mut HashMap::new(), f)
+    }
+}
+
+// Prints several types with their variables named together, so that a variable shared between
+// them gets the same name in each.
+fn show_types<const N: usize>(ts: [&Type; N]) -> [String; N] {
+    let mut names = HashMap::new();
+    ts.map(|t| {
+        let mut s = String::new();
+        write_type(t, &mut names, &mut s).expect("writing to a string failed");
+        s
+    })
+}
+
+// Prints a type, naming its variables in the order they are printed after those already named.
+fn write_type(
+    t: &Type,
+    names: &mut HashMap<usize, usize>,
+    f: &mut impl std::fmt::Write,
+) -> std::fmt::Result {
+    // Where a type appears, which decides whether it needs parentheses.
+    #[derive(Clone, Copy, PartialEq)]
+    enum Place {
+        Top,
+        Left,
+        Component,
+        Bang,
+    }
+
+    enum Item<'a> {
+        Type(&'a Type, Place),
+        Text(&'static str),
+    }
+
+    let mut stack = vec![Item::Type(t, Place::Top)];
+    while let Some(item) = stack.pop() {
+        let (t, place) = match item {
+            Item::Text(s) => {
+                write!(f, "{s}")?;
+                continue;
+            }
+            Item::Type(t, place) => (t, place),
+        };
+        match t {
+            Type::Qubit => write!(f, "qubit")?,
+            Type::Var(i) => {
+                let n = names.len();
+                write!(f, "{}", var_name(*names.entry(*i).or_insert(n)))?;
+            }
+            Type::Bang(a) => {
+                write!(f, "!")?;
+                stack.push(Item::Type(a, Place::Bang));
+            }
+            Type::Lolli(a, b) => {
+                let parens = place != Place::Top;
+                if parens {
+                    write!(f, "(")?;
+                    stack.push(Item::Text(")"));
+                }
+                stack.push(Item::Type(b, Place::Top));
+                stack.push(Item::Text(" ⊸ "));
+                stack.push(Item::Type(a, Place::Left));
+            }
+            Type::Tensor(ts) => {
+                let parens = matches!(place, Place::Component | Place::Bang);
+                if parens {
+                    write!(f, "(")?;
+                    stack.push(Item::Text(")"));
+                }
+                for (i, t) in ts.iter().enumerate().rev() {
+                    stack.push(Item::Type(t, Place::Component));
+                    if i > 0 {
+                        stack.push(Item::Text(" ⊗ "));
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::infer;
+    use crate::parser::parse;
+
+    fn error(source: &str) -> String {
+        let t = parse(&mut source.chars()).unwrap();
+        infer(&t, |_| None).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn variables_are_named_across_both_types_of_an_error() {
+        assert_eq!(
+            error("#x. #y. !(x) y"),
+            "!(x) of type !a cannot be applied to y of type b"
+        );
+        assert!(error("#x. x x").starts_with("x of type a cannot be applied to x of type a "));
+    }
+}
@@ -0,0 +1,143 @@
+use num::Complex;
+
+// How far a matrix product may stray from the identity while still counting as unitary. This is
+// loose enough to allow amplitudes written to four decimal places, such as 0.7071.
+const TOLERANCE: f64 = 1e-4;
+
+#[derive(Debug, Clone)]
+pub enum UnitaryError {
+    Malformed(String),
+    InvalidEntry(String),
+    NotSquare,
+    BadDimension(usize),
+    NotUnitary,
+}
+
+impl std::fmt::Display for UnitaryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UnitaryError::Malformed(text) => {
+                write!(
+                    f,
+                    "expected a matrix written as [[a, b], [c, d]], found {text}"
+                )
+            }
+            UnitaryError::InvalidEntry(text) => write!(f, "{text} is not a complex number"),
+            UnitaryError::NotSquare => write!(f, "the matrix is not square"),
+            UnitaryError::BadDimension(dim) => write!(
+                f,
+                "a {dim} by {dim} matrix doesn't act on a whole number of qubits"
+            ),
+            UnitaryError::NotUnitary => write!(f, "the matrix is not unitary"),
+        }
+    }
+}
+
+// A user-defined gate acting on some number of qubits, given by its unitary matrix in the
+// computational basis. The first qubit of a tuple is the most significant bit of the basis index.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Unitary {
+    qubits: usize,
+    matrix: Vec<Vec<Complex<f64>>>,
+}
+
+impl Unitary {
+    // Checks that the rows form a unitary matrix over a whole number of qubits.
+    pub fn new(matrix: Vec<Vec<Complex<f64>>>) -> Result<Self, UnitaryError> {
+        let dim = matrix.len();
+        if matrix.iter().any(|row| row.len() != dim) {
+            return Err(UnitaryError::NotSquare);
+        }
+        if dim < 2 || !dim.is_power_of_two() {
+            return Err(UnitaryError::BadDimension(dim));
+        }
+
+        // U is unitary exactly when the product of U with its conjugate transpose is the identity.
+        for i in 0..dim {
+            for j in 0..dim {
+                let dot: Complex<f64> = (0..dim).map(|k| matrix[i][k] * matrix[j][k].conj()).sum();
+                let expected = if i == j { 1.0 } else { 0.0 };
+                if (dot - expected).norm() > TOLERANCE {
+                    return Err(UnitaryError::NotUnitary);
+                }
+            }
+        }
+
+        Ok(Self {
+            qubits: dim.trailing_zeros() as usize,
+            matrix,
+        })
+    }
+
+    // Gets the number of qubits this gate acts on.
+    pub fn qubits(&self) -> usize {
+        self.qubits
+    }
+
+    // Gets the image of a basis state under this gate, as the nonzero amplitudes of each basis
+    // state.
+    pub fn apply(&self, bits: &[bool]) -> Vec<(Vec<bool>, Complex<f64>)> {
+        let col = bits.iter().fold(0, |acc, b| (acc << 1) | *b as usize);
+        (0..self.matrix.len())
+            .filter(|row| self.matrix[*row][col].norm_sqr() > 0.0)
+            .map(|row| {
+                let out = (0..self.qubits)
+                    .map(|i| (row >> (self.qubits - 1 - i)) & 1 == 1)
+                    .collect();
+                (out, self.matrix[row][col])
+            })
+            .collect()
+    }
+}
+
+// Parses a matrix written as a list of rows, like [[0, 1], [1, 0]]. Entries are complex numbers
+// such as 1, -0.5, 0.7071i or 0.5-0.5i.
+pub fn parse_unitary(text: &str) -> Result<Unitary, UnitaryError> {
+    let text: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+    let inner = text
+        .strip_prefix("[[")
+        .and_then(|t| t.strip_suffix("]]"))
+        .ok_or_else(|| UnitaryError::Malformed(text.clone()))?;
+
+    let mut matrix = Vec::new();
+    for row in inner.split("],[") {
+        if row.contains(['[', ']']) {
+            return Err(UnitaryError::Malformed(text.clone()));
+        }
+        let row = row
+            .split(',')
+            .map(parse_complex)
+            .collect::<Result<Vec<_>, _>>()?;
+        matrix.push(row);
+    }
+
+    Unitary::new(matrix)
+}
+
+// Parses a complex number written as a real part, an imaginary part ending in i, or both.
+fn parse_complex(text: &str) -> Result<Complex<f64>, UnitaryError> {
+    let invalid = || UnitaryError::InvalidEntry(text.to_string());
+    let Some(rest) = text.strip_suffix('i') else {
+        return text
+            .parse()
+            .map(|re| Complex::new(re, 0.0))
+            .map_err(|_| invalid());
+    };
+
+    // Split off the real part at the last sign that doesn't belong to an exponent.
+    let split = rest
+        .char_indices()
+        .rev()
+        .find(|(i, c)| (*c == '+' || *c == '-') && *i > 0 && !rest[..*i].ends_with(['e', 'E']))
+        .map(|(i, _)| i);
+    let (re, im) = match split {
+        Some(i) => (rest[..i].parse().map_err(|_| invalid())?, &rest[i..]),
+        None => (0.0, rest),
+    };
+    let im = match im {
+        "" | "+" => 1.0,
+        "-" => -1.0,
+        _ => im.parse().map_err(|_| invalid())?,
+    };
+    Ok(Complex::new(re, im))
+}